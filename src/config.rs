@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 use serde::Deserialize;
@@ -10,12 +11,160 @@ pub fn config_location() -> std::io::Result<PathBuf> {
 #[derive(Deserialize)]
 pub struct Config {
     pub theme: ConfigTheme,
+    #[serde(default)]
+    pub editor: ConfigEditor,
+    #[serde(default)]
+    pub markers: ConfigMarkers,
+    #[serde(default)]
+    pub lsp: ConfigLsp,
+    #[serde(default)]
+    pub filetype: HashMap<String, ConfigFiletype>, // Overrides by syntax name, matched ignoring case
+    #[serde(default = "default_pairs")]
+    pub pairs: HashMap<String, Vec<String>>, // Path ending to the endings of its paired files, tried in order
+}
+
+fn default_pairs() -> HashMap<String, Vec<String>> {
+    [
+        (".c", &[".h"][..]),
+        (".h", &[".c", ".cpp", ".cc"]),
+        (".cpp", &[".hpp", ".h"]),
+        (".hpp", &[".cpp"]),
+        (".cc", &[".hh", ".h"]),
+        (".hh", &[".cc"]),
+        (".rs", &["/mod.rs"]),
+        ("/mod.rs", &[".rs"]),
+        (".go", &["_test.go"]),
+        ("_test.go", &[".go"]),
+    ].iter().map(|(from, to)| (from.to_string(), to.iter().map(|s| s.to_string()).collect())).collect()
+}
+
+impl Config {
+    // Applies the [filetype.<name>] section for the syntax, if there is one
+    pub fn apply_filetype(&mut self, syntax: &str) {
+        let overrides = match self.filetype.iter().find(|(name, _)| name.eq_ignore_ascii_case(syntax)) {
+            Some((_, overrides)) => overrides,
+            None => return,
+        };
+        let editor = &mut self.editor;
+        if let Some(v) = overrides.tab_width { editor.tab_width = v; }
+        if let Some(v) = overrides.soft_tabs { editor.soft_tabs = v; }
+        if let Some(v) = overrides.auto_indent { editor.auto_indent = v; }
+        if let Some(v) = overrides.auto_pairs { editor.auto_pairs = v; }
+        if let Some(v) = overrides.continue_comments { editor.continue_comments = v; }
+        if let Some(v) = overrides.indent_guides { editor.indent_guides = v; }
+    }
 }
 
 #[derive(Deserialize)]
 pub struct ConfigTheme {
     pub name: String,
-    pub light_fix: bool,
+    // How much the gutter and header stand out from the background, in percent.
+    // Dark backgrounds are lightened by it, light ones darkened
+    #[serde(default = "default_shade")]
+    pub shade: f32,
+    // Colors for search matches as "#rrggbb", taken from the theme or the selection colors when unset
+    #[serde(default)]
+    pub find_bg: Option<String>,
+    #[serde(default)]
+    pub find_fg: Option<String>,
+}
+
+fn default_shade() -> f32 {
+    6.0
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ConfigEditor {
+    pub tab_width: usize,
+    pub soft_tabs: bool, // Indent with spaces, Tab inserts them up to the next tab stop
+    pub auto_indent: bool,
+    pub auto_pairs: bool,
+    pub continue_comments: bool,
+    pub warn_mixed_indent: bool,
+    pub indent_guides: bool,
+    pub min_gutter_width: usize,
+    pub autosave_secs: Option<u64>, // Save after this many seconds without input
+    pub swap_file: bool,
+    pub fsync_on_save: bool,
+    pub alt_screen: bool,
+    pub scroll_lines: usize, // Lines moved per mouse wheel step
+    pub page_overlap: usize, // Lines of the previous page still shown after PageUp/PageDown
+    pub undo_limit: usize, // Undo steps kept, older ones are forgotten
+    pub ignore_case: bool, // Searches are case-insensitive, alt-c toggles it
+    pub smartcase: bool, // Searches are case-insensitive unless they contain a capital
+    pub footer_separator: bool, // Draw a line between the text and the footer
+    pub popup_size: f32, // Popup width and height, in percent of the terminal's
+    pub scrollbar: bool, // Drawn over the last column, clicking it jumps through the file
+    pub wrap: bool, // Long lines continue on the rows below instead of running off the screen
+    pub visual_bell: bool, // Flash the header instead of ringing the terminal's bell
+}
+
+// Editor settings that can be set per filetype, unset ones keep the [editor] value
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ConfigFiletype {
+    pub tab_width: Option<usize>,
+    pub soft_tabs: Option<bool>,
+    pub auto_indent: Option<bool>,
+    pub auto_pairs: Option<bool>,
+    pub continue_comments: Option<bool>,
+    pub indent_guides: Option<bool>,
+}
+
+// Glyphs drawn for things that aren't text
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ConfigMarkers {
+    pub tab: String, // Last character ends the tab, the first fills the rest of it
+    pub empty_line: String, // Rows past the end of the file
+    pub indent_guide: String,
+}
+
+impl Default for ConfigMarkers {
+    fn default() -> Self {
+        Self {
+            tab: String::from("->"),
+            empty_line: String::from("~"),
+            indent_guide: String::from("│"),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ConfigLsp {
+    pub enabled: bool,
+    pub servers: HashMap<String, String>, // File extension to the command starting its language server
+}
+
+impl Default for ConfigEditor {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            soft_tabs: false,
+            auto_indent: true,
+            auto_pairs: false,
+            continue_comments: false,
+            warn_mixed_indent: true,
+            indent_guides: false,
+            min_gutter_width: 3,
+            autosave_secs: None,
+            swap_file: false,
+            fsync_on_save: false,
+            alt_screen: true,
+            scroll_lines: 3,
+            page_overlap: 2,
+            undo_limit: 1000,
+            ignore_case: false,
+            smartcase: false,
+            footer_separator: false,
+            popup_size: 67.0,
+            scrollbar: false,
+            wrap: false,
+            visual_bell: false,
+        }
+    }
 }
 
 impl Default for Config {
@@ -23,8 +172,15 @@ impl Default for Config {
         Self {
             theme: ConfigTheme {
                 name: String::from("gruvbox-dark"),
-                light_fix: false,
-            }
+                shade: default_shade(),
+                find_bg: None,
+                find_fg: None,
+            },
+            editor: ConfigEditor::default(),
+            markers: ConfigMarkers::default(),
+            lsp: ConfigLsp::default(),
+            filetype: HashMap::new(),
+            pairs: default_pairs(),
         }
     }
 }
@@ -1,9 +1,11 @@
-use std::io::{stdout};
-use std::path::Path;
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 
 use crossterm::{
 	terminal::*,
     cursor,
+    style::{Attribute, SetAttribute},
     ExecutableCommand, Result,
     event::*,
 };
@@ -15,16 +17,47 @@ use syntect::{
     parsing::SyntaxReference,
 };
 
+use unicode_width::UnicodeWidthChar;
+
 use crate::config::*;
+use crate::state::{self, Position};
+use crate::lsp::{Lsp, Diagnostic, Severity};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Bytes per row in the hex view, and digits in its offset gutter
+const HEX_ROW: usize = 16;
+const HEX_OFFSET_WIDTH: usize = 8;
+
+// Idle polls to wait before backing up the buffer to the swap file
+const SWAP_DELAY_TICKS: u32 = 10;
+
+// How many lines at the start and end of a file are searched for a modeline
+const MODELINE_LINES: usize = 5;
+
+// Files bigger than this get a loading message
+const LARGE_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
 #[derive(Copy, Clone)]
 pub enum PromptType {
     UnsavedQuit,
     Find,
+    Replace,
+    ReplaceAll,
+    ReplaceWith,
     Help,
     GoToLine,
+    FileChanged,
+    RecoverSwap,
+    RecentFiles,
+    LineEnding,
+    Reload,
+    Info,
+    Diff,
+    SaveAs,
+    Overwrite,
+    Open,
+    UnsavedOpen,
 }
 
 impl PromptType {
@@ -32,707 +65,3790 @@ impl PromptType {
         match self {
             Self::UnsavedQuit => "Unsaved changes, quit? (y/n)",
             Self::Find => "Search",
+            Self::Replace => "Replace",
+            Self::ReplaceAll => "Replace all",
+            Self::ReplaceWith => "Replace with",
             Self::Help => "Help!",
             Self::GoToLine => "Go to",
+            Self::FileChanged => "File changed on disk, load it? (y/n)",
+            Self::RecoverSwap => "Swap file found, recover unsaved changes? (y/n)",
+            Self::RecentFiles => "Recent files, open number:",
+            Self::LineEnding => "Convert line endings to (lf/crlf)",
+            Self::Reload => "Unsaved changes, reload from disk anyway? (y/n)",
+            Self::Info => "File info",
+            Self::Diff => "Unsaved changes",
+            Self::SaveAs => "Save as",
+            Self::Overwrite => "File exists, overwrite? (y/n)",
+            Self::Open => "Open",
+            Self::UnsavedOpen => "Unsaved changes, save them first? (y/n, Esc to cancel)",
         }
     }
 
+    // Whether Tab types a tab into the prompt, only searches have a use for one
+    fn takes_tab(&self) -> bool {
+        matches!(self, Self::Find | Self::Replace | Self::ReplaceAll | Self::ReplaceWith)
+    }
+
     fn description(&self) -> String {
         match self {
-            Self::Help => format!("Fox editor\nVersion {}\nConfig: {}\n\nCommands:\n ctrl-h: help\n ctrl-s: save\n ctrl-q: quit\n ctrl-f: search",
+            Self::RecentFiles => state::recent_files().iter().enumerate().map(|(i, path)| format!("{: >2}: {}\n", i + 1, path)).collect(),
+            Self::Help => format!("Fox editor\nVersion {}\nConfig: {}\n\nCommands:\n{}",
                             VERSION,
-                            config_location().map(|p| p.display().to_string()).unwrap_or(String::from("unavailable"))
+                            config_location().map(|p| p.display().to_string()).unwrap_or(String::from("unavailable")),
+                            command_help()
                           ),
             _ => String::new(),
         }
     }
 }
 
+// Things a shortcut key can do
+#[derive(Copy, Clone, PartialEq)]
+enum Command {
+    Help,
+    Save,
+    SaveAs,
+    Open,
+    Quit,
+    Find,
+    FindPrevious,
+    ToggleCase,
+    Replace,
+    ReplaceAll,
+    GoToLine,
+    SelectLine,
+    DedentCommon,
+    Copy,
+    Cut,
+    Paste,
+    PasteBlock,
+    PasteRegister,
+    Undo,
+    Redo,
+    Repeat,
+    OpenLineBelow,
+    OpenLineAbove,
+    SwapUp,
+    SwapDown,
+    WordLeft,
+    WordRight,
+    SelectWordLeft,
+    SelectWordRight,
+    DeleteWord,
+    Dedent,
+    StartOfFile,
+    EndOfFile,
+    SelectToStartOfFile,
+    SelectToEndOfFile,
+    Verbatim,
+    RecentFiles,
+    Info,
+    Diff,
+    OpenPair,
+    Reload,
+    LineEnding,
+    JumpToTag,
+    JumpBack,
+}
+
+impl Command {
+    fn description(&self) -> &'static str {
+        match self {
+            Self::Help => "help",
+            Self::Save => "save",
+            Self::SaveAs => "save as",
+            Self::Open => "open a file",
+            Self::Quit => "quit",
+            Self::Find => "search",
+            Self::FindPrevious => "previous match",
+            Self::ToggleCase => "toggle ignoring case in searches",
+            Self::Replace => "replace, a match at a time",
+            Self::ReplaceAll => "replace all",
+            Self::GoToLine => "go to line",
+            Self::SelectLine => "select line",
+            Self::DedentCommon => "remove common indentation",
+            Self::Copy => "copy the selection, or the line",
+            Self::Cut => "cut the selection, or the line",
+            Self::Paste => "paste",
+            Self::PasteBlock => "paste at the cursor column",
+            Self::PasteRegister => "paste last copy (internal register)",
+            Self::Undo => "undo",
+            Self::Redo => "redo",
+            Self::Repeat => "repeat last edit",
+            Self::OpenLineBelow => "open line below",
+            Self::OpenLineAbove => "open line above",
+            Self::SwapUp => "move line up",
+            Self::SwapDown => "move line down",
+            Self::WordLeft => "previous word",
+            Self::WordRight => "next word",
+            Self::SelectWordLeft => "select to previous word",
+            Self::SelectWordRight => "select to next word",
+            Self::DeleteWord => "delete previous word",
+            Self::Dedent => "dedent",
+            Self::StartOfFile => "start of file",
+            Self::EndOfFile => "end of file",
+            Self::SelectToStartOfFile => "select to start of file",
+            Self::SelectToEndOfFile => "select to end of file",
+            Self::Verbatim => "insert next key literally",
+            Self::RecentFiles => "recent files",
+            Self::Info => "file info",
+            Self::Diff => "show unsaved changes",
+            Self::OpenPair => "open paired file",
+            Self::Reload => "reload from disk",
+            Self::LineEnding => "convert line endings",
+            Self::JumpToTag => "jump to tag",
+            Self::JumpBack => "jump back",
+        }
+    }
+}
+
+const CTRL: KeyModifiers = KeyModifiers::CONTROL;
+const CTRL_SHIFT: KeyModifiers = KeyModifiers::CONTROL.union(KeyModifiers::SHIFT);
+const ALT: KeyModifiers = KeyModifiers::ALT;
+const ALT_SHIFT: KeyModifiers = KeyModifiers::ALT.union(KeyModifiers::SHIFT);
+const SHIFT: KeyModifiers = KeyModifiers::SHIFT;
+const NONE: KeyModifiers = KeyModifiers::NONE;
+
+// Shortcut keys, in the order the help popup lists them
+const KEYBINDINGS: &[(KeyModifiers, KeyCode, Command)] = &[
+    (CTRL, KeyCode::Char('h'), Command::Help),
+    (CTRL, KeyCode::Char('s'), Command::Save),
+    (CTRL_SHIFT, KeyCode::Char('s'), Command::SaveAs),
+    (CTRL, KeyCode::Char('o'), Command::Open),
+    (CTRL, KeyCode::Char('q'), Command::Quit),
+    (CTRL, KeyCode::Char('f'), Command::Find),
+    (CTRL_SHIFT, KeyCode::Char('f'), Command::FindPrevious),
+    (ALT, KeyCode::Char('c'), Command::ToggleCase),
+    (CTRL, KeyCode::Char('g'), Command::Replace),
+    (CTRL_SHIFT, KeyCode::Char('g'), Command::ReplaceAll),
+    (CTRL, KeyCode::Char('k'), Command::GoToLine),
+    (CTRL, KeyCode::Char('l'), Command::SelectLine),
+    (CTRL, KeyCode::Char('d'), Command::DedentCommon),
+    (CTRL, KeyCode::Char('c'), Command::Copy),
+    (CTRL, KeyCode::Char('x'), Command::Cut),
+    (CTRL, KeyCode::Char('v'), Command::Paste),
+    (CTRL_SHIFT, KeyCode::Char('v'), Command::PasteBlock),
+    (ALT, KeyCode::Char('p'), Command::PasteRegister),
+    (CTRL, KeyCode::Char('z'), Command::Undo),
+    (CTRL, KeyCode::Char('y'), Command::Redo),
+    (CTRL_SHIFT, KeyCode::Char('z'), Command::Redo),
+    (CTRL, KeyCode::Char('r'), Command::Repeat),
+    (CTRL, KeyCode::Char('n'), Command::OpenLineBelow),
+    (CTRL, KeyCode::Enter, Command::OpenLineBelow),
+    (CTRL, KeyCode::Char('p'), Command::OpenLineAbove),
+    (CTRL, KeyCode::Up, Command::SwapUp),
+    (ALT, KeyCode::Up, Command::SwapUp),
+    (CTRL, KeyCode::Down, Command::SwapDown),
+    (ALT, KeyCode::Down, Command::SwapDown),
+    (CTRL, KeyCode::Left, Command::WordLeft),
+    (ALT, KeyCode::Left, Command::WordLeft),
+    (CTRL, KeyCode::Right, Command::WordRight),
+    (ALT, KeyCode::Right, Command::WordRight),
+    (CTRL_SHIFT, KeyCode::Left, Command::SelectWordLeft),
+    (ALT_SHIFT, KeyCode::Left, Command::SelectWordLeft),
+    (CTRL_SHIFT, KeyCode::Right, Command::SelectWordRight),
+    (ALT_SHIFT, KeyCode::Right, Command::SelectWordRight),
+    (CTRL, KeyCode::Backspace, Command::DeleteWord),
+    (ALT, KeyCode::Backspace, Command::DeleteWord),
+    (SHIFT, KeyCode::BackTab, Command::Dedent),
+    (CTRL, KeyCode::Home, Command::StartOfFile),
+    (CTRL, KeyCode::End, Command::EndOfFile),
+    (CTRL_SHIFT, KeyCode::Home, Command::SelectToStartOfFile),
+    (CTRL_SHIFT, KeyCode::End, Command::SelectToEndOfFile),
+    (ALT, KeyCode::Char('v'), Command::Verbatim),
+    (CTRL, KeyCode::Char('e'), Command::RecentFiles),
+    (NONE, KeyCode::F(2), Command::Info),
+    (NONE, KeyCode::F(3), Command::Diff),
+    (NONE, KeyCode::F(4), Command::OpenPair),
+    (NONE, KeyCode::F(5), Command::Reload),
+    (NONE, KeyCode::F(6), Command::LineEnding),
+    (NONE, KeyCode::F(12), Command::JumpToTag),
+    (CTRL, KeyCode::Char('t'), Command::JumpBack),
+];
+
+// The command bound to a key press, if any
+fn command_for(key: &KeyEvent) -> Option<Command> {
+    // Shifted letters come as capitals from some terminals and lowercase from others
+    let code = match key.code {
+        KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+        code => code,
+    };
+    KEYBINDINGS.iter().find(|(modifiers, bound, _)| *modifiers == key.modifiers && *bound == code).map(|(_, _, command)| *command)
+}
+
+fn key_name(modifiers: KeyModifiers, code: KeyCode) -> String {
+    let mut name = String::new();
+    if modifiers.contains(KeyModifiers::CONTROL) { name.push_str("ctrl-"); }
+    if modifiers.contains(KeyModifiers::ALT) { name.push_str("alt-"); }
+    if modifiers.contains(KeyModifiers::SHIFT) { name.push_str("shift-"); }
+    match code {
+        KeyCode::Char(c) => name.push(c),
+        KeyCode::F(n) => name.push_str(&format!("F{}", n)),
+        KeyCode::Enter => name.push_str("Enter"),
+        KeyCode::BackTab => name.push_str("Tab"),
+        code => name.push_str(&format!("{:?}", code)),
+    }
+    name
+}
+
+// One line per command with all the keys bound to it, made from the keybinding table so it can't go out of date
+fn command_help() -> String {
+    let mut help = String::new();
+    let mut listed = Vec::new();
+    for (_, _, command) in KEYBINDINGS {
+        if listed.contains(command) {
+            continue;
+        }
+        listed.push(*command);
+        let keys: Vec<String> = KEYBINDINGS.iter().filter(|(_, _, c)| c == command).map(|(m, k, _)| key_name(*m, *k)).collect();
+        help.push_str(&format!(" {}: {}\n", keys.join(", "), command.description()));
+    }
+    help
+}
+
 #[derive(Clone)]
 pub struct Prompt {
     pub prompt: PromptType,
     pub buf: String,
+    pub description: String,
+    pub scroll: usize, // First description row shown by a popup
 }
 
-pub struct Fox {
-    path_expanded: String,
-    path: String,
-    text: Vec<String>,
-    cursor: (u16, u16),
-    highlight: (u16, u16),
-    scroll: u16,
+// What kind of edit an undo step was made for, decides which edits get merged into one step
+#[derive(Copy, Clone, PartialEq)]
+enum EditKind {
+    Word,
+    Space,
+    Delete,
+    Other,
+}
 
-    dirty: bool,
-    prompt: Option<Prompt>,
-    popup: Option<Prompt>,
-    status: String,
+// Most recent edit, replayed by the repeat command
+#[derive(Clone)]
+enum Action {
+    Insert(String),
+    Backspace(usize),
+    Delete(usize),
+}
 
-    syntax: SyntaxReference,
-    theme: Theme,
+struct Snapshot {
+    text: Vec<String>,
+    endings: Vec<LineEnding>,
+    cursor: (usize, usize),
+    highlight: (usize, usize),
+    scroll: usize,
+}
 
-    bg: Color,
-    fg: Color,
-    gutter_bg: Color,
-    gutter_fg: Color,
-    highlight_bg: Color,
-    highlight_fg: Color,
-    header_bg: Color,
+// Column of a byte's first hex digit within a hex view row
+fn hex_column(i: usize) -> usize {
+    i * 3 + if i >= HEX_ROW / 2 { 1 } else { 0 }
 }
 
-impl Fox {
-    pub fn new(filename: &str) -> Result<Self> {
-        let config = config();
-        let default_config = Config::default();
+// Number of columns a tab starting at `col` takes up
+fn tab_stop(col: usize, tab_width: usize) -> usize {
+    tab_width - col % tab_width
+}
 
-        stdout().execute(EnterAlternateScreen)?;
-        // stdout().execute(cursor::SetCursorShape(cursor::CursorShape::Line))?;
-        enable_raw_mode()?;
+// Columns `c` takes up on screen, two for wide chars like CJK and none for combining marks. Not for tabs
+fn char_width(c: char) -> usize {
+    UnicodeWidthChar::width(c).unwrap_or(0)
+}
 
-        let filename_expanded = shellexpand::full(filename).map(|s| s.to_string()).unwrap_or(filename.to_string());
-        let path = Path::new(&filename_expanded);
-        let text: Vec<String> = if path.exists() { // Perhaps try_exists is better here
-            std::fs::read_to_string(path).expect("File exists but cannot be opened for unknown reasons!").lines().map(|l| l.to_string()).collect()
-        } else {
-            vec![String::new()]
-        };
+// Falls back to 80x24 when there's no terminal to ask
+fn terminal_size() -> (u16, u16) {
+    size().unwrap_or((80, 24))
+}
 
-        let ps = &carbon_dump::SYNTAX_SET;
-        let ts = &carbon_dump::THEME_SET;
-        let syntax = if let Some(extension) = &path.extension().map(|s| s.to_str().expect("Unparsable extension!")) {
-            ps.find_syntax_by_extension(&extension).unwrap_or_else(|| ps.find_syntax_plain_text())
-        } else if let Some(filename) = &path.file_name().map(|s| s.to_str().expect("Unparsable filename!")) {
-            ps.find_syntax_by_extension(&filename).unwrap_or_else(|| ps.find_syntax_plain_text())
-        } else {
-            ps.find_syntax_plain_text()
-        };
-        let theme = ts.themes.get(&config.theme.name).unwrap_or_else(|| &ts.themes[&default_config.theme.name]); // gruvbox-dark
-        let theme_is_dark = !config.theme.light_fix;
+// Column reached after drawing `text` starting at `col`
+fn advance_col(text: &str, col: usize, tab_width: usize) -> usize {
+    text.chars().fold(col, |col, c| if c == '\t' { col + tab_stop(col, tab_width) } else { col + char_width(c) })
+}
 
-        let bg = theme.settings.background.unwrap_or(Color::BLACK);
-        let fg = theme.settings.foreground.unwrap_or(Color::WHITE);
-        let gutter_bg_mul = if theme_is_dark { 4.0 } else { 2.0 };
-        let gutter_bg = theme.settings.gutter.unwrap_or(Color {
-            r: (bg.r as f32 / 3.0 * gutter_bg_mul) as u8,
-            g: (bg.g as f32 / 3.0 * gutter_bg_mul) as u8,
-            b: (bg.b as f32 / 3.0 * gutter_bg_mul) as u8,
-            a: bg.a,
-        });
-        let gutter_fg = theme.settings.gutter_foreground.unwrap_or(fg);
-        let highlight_bg_default = if theme_is_dark { 48 } else { 132 };
-        let highlight_fg_default = if theme_is_dark { 160 } else { 48 };
-        let highlight_bg = theme.settings.selection.unwrap_or(theme.settings.highlight.unwrap_or(theme.settings.line_highlight.unwrap_or(theme.settings.find_highlight.unwrap_or(Color {
-            r: highlight_bg_default,
-            g: highlight_bg_default,
-            b: highlight_bg_default,
-            a: bg.a,
-        }))));
-        let highlight_fg = theme.settings.selection_foreground.unwrap_or(Color {
-            r: highlight_fg_default,
-            g: highlight_fg_default,
-            b: highlight_fg_default,
-            a: fg.a,
-        });
-        let header_bg_mul = if theme_is_dark { 5.0 } else { 1.5 };
-        let header_bg = Color {
-            r: (bg.r as f32 / 3.0 * header_bg_mul) as u8,
-            g: (bg.g as f32 / 3.0 * header_bg_mul) as u8,
-            b: (bg.b as f32 / 3.0 * header_bg_mul) as u8,
-            a: bg.a,
-        };
+// The char column drawn at `col`, or the one of the char covering it like a tab
+fn char_at_col(line: &str, col: usize, tab_width: usize) -> usize {
+    let mut drawn = 0;
+    let mut x = 0;
+    for c in line.chars() {
+        drawn = if c == '\t' { drawn + tab_stop(drawn, tab_width) } else { drawn + char_width(c) };
+        if drawn > col {
+            break;
+        }
+        x += 1;
+    }
+    x
+}
 
-        Ok(Self {
-            path_expanded: filename_expanded,
-            path: filename.to_string(),
-            text: text,
-            cursor: (0,0),
-            highlight: (0,0),
-            scroll: 0,
+// Cursor columns count chars, these convert them to and from byte offsets for slicing
 
-            dirty: false,
-            prompt: None,
-            popup: None,
-            status: String::new(),
+// Byte offset of column `x` in `line`, its length if `x` is past the end
+fn byte_index(line: &str, x: usize) -> usize {
+    line.char_indices().nth(x).map_or(line.len(), |(i, _)| i)
+}
 
-            syntax: syntax.clone(),
-            theme: theme.clone(),
+// Column of byte offset `i` in `line`
+fn char_col(line: &str, i: usize) -> usize {
+    line[..i].chars().count()
+}
 
-            bg: bg,
-            fg: fg,
-            gutter_bg: gutter_bg,
-            gutter_fg: gutter_fg,
-            highlight_bg: highlight_bg,
-            highlight_fg: highlight_fg,
-            header_bg: header_bg,
-        })
-    }
+// Column after the last char of `line`
+fn line_len(line: &str) -> usize {
+    line.chars().count()
+}
 
-    pub fn redraw(&mut self) -> Result<()> {
-        use std::io::Write;
-        use owo_colors::OwoColorize;
+// Starts a new row when a cell `cell_width` wide at `col` doesn't fit in the current one, returns whether it did
+fn wrap_cell(starts: &mut Vec<usize>, col: usize, cell_width: usize, width: usize) -> bool {
+    let start = *starts.last().unwrap();
+    let breaks = col > start && col + cell_width - start > width;
+    if breaks {
+        starts.push(col);
+    }
+    breaks
+}
 
-        stdout().execute(cursor::Hide)?;
+// Columns each row of `line` starts at when wrapping at `width`. A wide char that doesn't fit at the
+// end of a row starts the next one, and a line filling its last row gets an empty one after it,
+// that's where the cursor goes at its end
+fn row_starts(line: &str, width: usize, tab_width: usize) -> Vec<usize> {
+    let mut starts = vec![0];
+    let mut col = 0;
+    for c in line.chars() {
+        // Tabs are drawn as single columns, so they can be split over two rows
+        let (cells, cell_width) = if c == '\t' { (tab_stop(col, tab_width), 1) } else { (1, char_width(c)) };
+        for _ in 0..cells {
+            wrap_cell(&mut starts, col, cell_width, width);
+            col += cell_width;
+        }
+    }
+    if col - starts.last().unwrap() == width {
+        starts.push(col);
+    }
+    starts
+}
 
-        let terminal_size = size()?;
+// Row and column in it that column `col` of a line is drawn at, given the line's row_starts()
+fn wrapped_col(col: usize, starts: &[usize]) -> (usize, usize) {
+    let row = starts.iter().rposition(|&start| start <= col).unwrap_or(0);
+    (row, col - starts[row])
+}
 
-        // Header
-        stdout().execute(cursor::MoveTo(0,0))?;
-        let mut filename = self.path[..self.path.len().min(terminal_size.0 as usize)].to_string();
-        if self.dirty { filename.push('*'); }
-        let offset = (terminal_size.0 as usize - filename.len()) / 2;
-        for _ in 0..offset {
-            print!("{}", " ".on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
-        }
-        stdout().execute(cursor::MoveTo(offset as u16,0))?;
-        print!("{}", filename.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
-        for _ in 0..offset + filename.len() % 1 + 1 {
-            print!("{}", " ".on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
+// Cuts a drawn line into rows the way row_starts() does
+fn wrap_ranges(ranges: &[(Style, String)], width: usize) -> Vec<Vec<(Style, String)>> {
+    let mut rows = vec![Vec::new()];
+    let mut starts = vec![0];
+    let mut col = 0;
+    for (style, s) in ranges {
+        let mut piece = String::new();
+        for c in s.chars() {
+            if wrap_cell(&mut starts, col, char_width(c), width) {
+                rows.last_mut().unwrap().push((*style, std::mem::take(&mut piece)));
+                rows.push(Vec::new());
+            }
+            piece.push(c);
+            col += char_width(c);
         }
+        rows.last_mut().unwrap().push((*style, piece));
+    }
+    if col - starts.last().unwrap() == width {
+        rows.push(Vec::new());
+    }
+    rows
+}
 
-        // Content
-        let mut h = HighlightLines::new(&self.syntax, &self.theme);
-        fn num_digits(n: u64, b: u32) -> u32 {
-            (n as f64).log(b as f64).ceil() as u32
+// `text` as drawn from `col` on, tabs turned into spaces
+fn expand_segment(text: &str, col: usize, tab_width: usize) -> String {
+    let mut expanded = String::new();
+    let mut col = col;
+    for c in text.chars() {
+        if c == '\t' {
+            let n = tab_stop(col, tab_width);
+            expanded.push_str(&" ".repeat(n));
+            col += n;
+        } else {
+            expanded.push(c);
+            col += char_width(c);
         }
-        let width = (num_digits((self.scroll as usize + terminal_size.1 as usize) as u64, 10) + 1) as usize;
-        for i in 1..terminal_size.1-1 {
-            let line_num = i as usize + self.scroll as usize;
-            stdout().execute(cursor::MoveTo(0,i))?;
-            if let Some(line) = self.text.get(line_num-1) {
-                print!("{}", format!(" {: >width$} ", line_num, width=width).truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
-
-                // let line = &line[..line.len().min(terminal_size.0 as usize - width - 2)];
-                let ranges: Vec<(Style, &str)> = h.highlight(&line, &carbon_dump::SYNTAX_SET);
-                let line = as_24_bit_terminal_escaped(&ranges[..], true);
-                let line = line.replace('\t', &format!("{}", "--->".truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b)));
-                print!("{}", line);
+    }
+    expanded
+}
 
-                //Finish line
-                for _ in cursor::position()?.0 .. terminal_size.0 { print!("{}", " ".on_truecolor(self.bg.r, self.bg.g, self.bg.b)); }
-            } else {
-                print!("{}", format!(" {: >width$} ", line_num, width=width).truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
-                print!("{}", "~".truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.bg.r, self.bg.g, self.bg.b));
-                //Finish line
-                for _ in cursor::position()?.0 .. terminal_size.0 { print!("{}", " ".on_truecolor(self.bg.r, self.bg.g, self.bg.b)); }
-            }
-        }
+// A tab's marker filling `width` columns
+fn tab_marker(marker: &str, width: usize) -> String {
+    let mut chars = marker.chars();
+    match (chars.next(), chars.last()) {
+        (Some(fill), Some(head)) => format!("{}{}", fill.to_string().repeat(width - 1), head),
+        (Some(head), None) => format!("{: <1$}", head, width),
+        (None, _) => " ".repeat(width),
+    }
+}
 
-        // Highlight
-        if self.highlight != self.cursor {
-            // let hpos_y = if self.scroll > self.highlight.1 { 0 } else { self.highlight.1 - self.scroll } + 1;
-            // let cpos_y = if self.scroll > self.cursor.1 { 0 } else { self.cursor.1 - self.scroll } + 1;
-            if self.highlight.1 == self.cursor.1 {
-                // Single line selection
-                if let Some(line) = self.text.get(self.cursor.1 as usize) {
-                    let min_x = self.highlight.0.min(self.cursor.0) as usize;
-                    let max_x = self.highlight.0.max(self.cursor.0) as usize;
-                    let text = &line[min_x..max_x];
-                    let cpos_y = if self.scroll > self.cursor.1 { 0 } else { self.cursor.1 - self.scroll } + 1;
-                    stdout().execute(cursor::MoveTo((min_x+width+2) as u16, cpos_y))?;
-                    print!("{}", text.truecolor(self.highlight_fg.r, self.highlight_fg.g, self.highlight_fg.b).on_truecolor(self.highlight_bg.r, self.highlight_bg.g, self.highlight_bg.b));
+// Replaces tabs with an arrow that reaches the next tab stop, and draws
+// indentation guides on the tab stops in the leading whitespace
+fn expand_tabs(ranges: &[(Style, &str)], tab_width: usize, marker: Color, markers: &ConfigMarkers, guides: bool) -> Vec<(Style, String)> {
+    let guide = markers.indent_guide.chars().next().unwrap_or(' ');
+    let mut col = 0;
+    let mut indent = true;
+    let mut result = Vec::new();
+    for (style, text) in ranges {
+        let mut run = String::new();
+        for c in text.chars() {
+            indent &= c == ' ' || c == '\t';
+            let guide_here = guides && indent && col % tab_width == 0;
+            if c == '\t' || guide_here {
+                if !run.is_empty() {
+                    result.push((*style, std::mem::take(&mut run)));
                 }
+                let mut marked = if c == '\t' { tab_marker(&markers.tab, tab_stop(col, tab_width)) } else { String::from(" ") };
+                if guide_here {
+                    marked.replace_range(..marked.chars().next().map_or(0, char::len_utf8), &guide.to_string());
+                }
+                col += marked.chars().count();
+                result.push((Style { foreground: marker, ..*style }, marked));
             } else {
-                // Multi line selection
-                todo!();
-                // let min_y = self.highlight.1.min(self.cursor.1);
-                // let max_y = self.highlight.1.max(self.cursor.1);
-                // for i in min_y..max_y {
-                //
-                // }
+                run.push(c);
+                col += char_width(c);
             }
         }
-
-        // Footer
-        stdout().execute(cursor::MoveTo(0,terminal_size.1))?;
-        for _ in 0..terminal_size.0 { print!("{}", " ".on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b)); }
-        stdout().execute(cursor::MoveTo(0,terminal_size.1))?;
-
-        // Status/prompt
-        if let Some(prompt) = &self.prompt {
-            print!("{}", format!("{}: ", prompt.prompt.text()).truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
-            print!("{}", prompt.buf.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
-        } else {
-            print!("{}", self.status.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
-            self.status = String::new();
+        if !run.is_empty() {
+            result.push((*style, run));
         }
+    }
+    result
+}
 
-        // Cursor location
-        let footer_loc = format!("{}:{}", self.cursor.0+1, self.cursor.1+1);
-        stdout().execute(cursor::MoveTo(terminal_size.0-footer_loc.len() as u16,terminal_size.1))?;
-        print!("{}", footer_loc.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
-
-        // Popup rendering
-        if let Some(popup) = &self.popup {
-            let (w,h) = terminal_size;
-            let x = w / 6;
-            let y = h / 6;
-            let w = w / 6 * 4;
-            let h = h / 6 * 4;
-            for i in 0..h {
-                stdout().execute(cursor::MoveTo(x,y+i))?;
-                for _ in 0..w {
-                    print!("{}", " ".on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
-                }
-            }
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
 
-            let max_text_width = (w - 2) as usize;
-            let title = popup.prompt.text();
-            let len = title.len().min(max_text_width);
-            let title = &title[..len];
-            let offset = (max_text_width - len) / 2 - len % 2;
-            stdout().execute(cursor::MoveTo(x+1+offset as u16,y+1))?;
-            print!("{}", title.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+fn closing_pair(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' | '\'' | '`' => Some(c),
+        _ => None,
+    }
+}
 
-            let desc = popup.prompt.description();
-            let description: Vec<&str> = desc.lines().collect();
-            for i in 0..description.len().min((h.max(3)-3) as usize) {
-                let line = description[i];
-                stdout().execute(cursor::MoveTo(x+1,y+3+i as u16))?;
-                print!("{}", line.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
-            }
-        }
+// Token starting a line comment in a syntax, by the syntax's name
+fn line_comment(syntax: &str) -> Option<&'static str> {
+    match syntax {
+        "Rust" | "C" | "C++" | "C#" | "Objective-C" | "Objective-C++" | "Java" | "JavaScript" | "JavaScript (Babel)" | "TypeScript"
+            | "TypeScriptReact" | "Go" | "D" | "Scala" | "Kotlin" | "Swift" | "Dart" | "Groovy" | "PHP" | "GLSL" | "HLSL"
+            | "Protocol Buffer" => Some("//"),
+        "Python" | "Ruby" | "Perl" | "R" | "Bourne Again Shell (bash)" | "friendly interactive shell (fish)" | "Makefile" | "YAML"
+            | "TOML" | "CMake" | "Dockerfile" | "Nim" | "Elixir" | "Julia" | "Crystal" | "Nix" | "Starlark" | "PowerShell" | "Tcl"
+            | "Generic Config" | "Git Config" | "Git Ignore" => Some("#"),
+        "Lua" | "SQL" | "Haskell" | "Elm" => Some("--"),
+        "Lisp" | "Clojure" | "Assembly x86 (NASM)" | "INI" => Some(";"),
+        "TeX" | "LaTeX" | "Erlang" | "MATLAB" | "Postscript" => Some("%"),
+        "VimL" => Some("\""),
+        _ => None,
+    }
+}
 
-        // Move cursor to show typing location
-        let cpos_y = if self.scroll > self.cursor.1 { 0 } else { self.cursor.1 - self.scroll } + 1;
-        if cpos_y < 1 || cpos_y >= terminal_size.1-1 {
-            stdout().execute(cursor::Hide)?;
-        } else {
-            if self.highlight == self.cursor { stdout().execute(cursor::Show)?; } else { stdout().execute(cursor::Hide)?; }
-            let tab_count = self.text[self.cursor.1 as usize][..self.cursor.0 as usize].matches("\t").count();
-            let tab_offset = tab_count * 3;
-            stdout().execute(cursor::MoveTo(self.cursor.0 + width as u16 + 2 + tab_offset as u16, cpos_y))?;
-        }
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
 
-        stdout().flush()?;
-        Ok(())
+// Offset of the next (or previous) word boundary from `x`
+fn word_boundary(line: &str, x: usize, forward: bool) -> usize {
+    if forward {
+        let rest = line[x..].trim_start_matches(|c| !is_word_char(c)).trim_start_matches(is_word_char);
+        line.len() - rest.len()
+    } else {
+        line[..x].trim_end_matches(|c| !is_word_char(c)).trim_end_matches(is_word_char).len()
     }
+}
 
-    pub fn prompt(&mut self, prompt: PromptType) {
-        self.prompt = Some(Prompt {
-            prompt: prompt,
-            buf: String::new(),
-        });
-    }
+// The word touching `x`, empty if there is none
+fn word_at(line: &str, x: usize) -> &str {
+    let start = line[..x].trim_end_matches(is_word_char).len();
+    let end = line.len() - line[x..].trim_start_matches(is_word_char).len();
+    &line[start..end]
+}
 
-    pub fn popup(&mut self, popup: PromptType) {
-        self.popup = Some(Prompt {
-            prompt: popup,
-            buf: String::new(),
-        });
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
     }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
 
-    pub fn save(&mut self) -> Result<()> {
-        std::fs::write(&self.path_expanded, self.text.join("\n"))?;
-        self.dirty = false;
-        self.status = String::from("Saved!");
-        Ok(())
+// Files are remembered by their absolute path
+fn position_key(path: &Path) -> String {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()).display().to_string()
+}
+
+// Follows symlinks (even dangling ones) so saving writes to the link's target
+fn resolve_symlinks(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf();
+    for _ in 0..40 {
+        match std::fs::read_link(&path) {
+            Ok(target) => path = path.parent().map(|p| p.join(&target)).unwrap_or(target),
+            Err(_) => break,
+        }
     }
+    path
+}
 
-    fn find_from(&mut self, s: &str, y: usize) -> bool {
-        for i in y..self.text.len() {
-            if let Some(line) = self.text.get(i) {
-                let mut line = line.to_string();
-                if i == self.cursor.1 as usize {
-                    line = line[self.cursor.0 as usize..].to_string();
-                }
-                if let Some(x) = line.find(s) {
-                    self.highlight.0 = x as u16;
-                    self.cursor.0 = self.highlight.0 + s.len() as u16;
-                    self.cursor.1 = i as u16;
-                    self.highlight.1 = self.cursor.1;
+// Whether renaming a file we made over `original` would change more than its contents:
+// other hard links to it would keep the old text, and someone else's file would become ours
+#[cfg(unix)]
+fn unsafe_to_replace(original: &std::fs::Metadata, ours: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    original.nlink() > 1 || original.uid() != ours.uid() || original.gid() != ours.gid()
+}
 
-                    // Calculate scroll - little bit fucked up rn lol
-                    let (_, mut height) = size().expect("Failed to query terminal size!");
-                    height -= 3;
-                    if self.cursor.1 as i16 - self.scroll as i16 > height as i16 {
-                        self.scroll += ((self.cursor.1 as i16 - self.scroll as i16) - height as i16) as u16;
-                    } else if (self.cursor.1 as i16 - self.scroll as i16) < 0 {
-                        self.scroll = 0;
-                        if self.cursor.1 as i16 - self.scroll as i16 > height as i16 {
-                            self.scroll += ((self.cursor.1 as i16 - self.scroll as i16) - height as i16) as u16;
-                        }
-                    }
+#[cfg(not(unix))]
+fn unsafe_to_replace(_original: &std::fs::Metadata, _ours: &std::fs::Metadata) -> bool {
+    false
+}
 
-                    return true;
-                }
-            }
+fn write_contents(file: &mut std::fs::File, contents: &str, sync: bool) -> std::io::Result<()> {
+    file.write_all(contents.as_bytes())?;
+    if sync { file.sync_all() } else { Ok(()) }
+}
+
+// Writes to a temporary file next to the target and renames it over the original,
+// keeping the original's permission bits. Files we aren't allowed to write are refused, even though the
+// rename would only need the directory to be writable, and files the rename can't replace safely are
+// written in place. With `sync` the data and the rename are flushed to disk before returning
+fn write_file(path: &Path, contents: &str, sync: bool) -> std::io::Result<()> {
+    let path = resolve_symlinks(path);
+    let original = std::fs::metadata(&path).ok();
+    if original.is_some() {
+        std::fs::OpenOptions::new().write(true).open(&path)?;
+    }
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let tmp = path.with_file_name(format!(".{}.fox-tmp", name));
+    let mut file = match std::fs::File::create(&tmp) {
+        Ok(file) => file,
+        // Can't make files in the directory, but the file itself is writable
+        Err(_) if original.is_some() => return write_contents(&mut std::fs::OpenOptions::new().write(true).truncate(true).open(&path)?, contents, sync),
+        Err(e) => return Err(e),
+    };
+    if let (Some(original), Ok(ours)) = (&original, file.metadata()) {
+        if unsafe_to_replace(original, &ours) {
+            drop(file);
+            let _ = std::fs::remove_file(&tmp);
+            return write_contents(&mut std::fs::OpenOptions::new().write(true).truncate(true).open(&path)?, contents, sync);
         }
-        false
     }
+    let result = write_contents(&mut file, contents, sync)
+        .and_then(|_| original.map_or(Ok(()), |m| std::fs::set_permissions(&tmp, m.permissions())))
+        .and_then(|_| std::fs::rename(&tmp, &path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+        return result;
+    }
+    match path.parent() {
+        Some(dir) if sync => {
+            let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+            std::fs::File::open(dir).and_then(|dir| dir.sync_all())
+        },
+        _ => Ok(()),
+    }
+}
 
-    pub fn find_next(&mut self, s: &str) -> bool {
-        if !self.find_from(s, self.cursor.1 as usize) {
-            if !self.find_from(s, 0) {
-                return false;
+// Looks `name` up in the nearest ctags file above `path`, returns the file the tag is in and its address
+fn find_tag(path: &Path, name: &str) -> Option<(PathBuf, String)> {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    path.ancestors().skip(1).find_map(|dir| {
+        let tags = std::fs::read_to_string(dir.join("tags")).ok()?;
+        tags.lines().filter(|line| !line.starts_with("!_TAG_")).find_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(tag), Some(file), Some(address)) if tag == name => Some((dir.join(file), address.to_string())),
+                _ => None,
             }
+        })
+    })
+}
+
+// Line a ctags address points to, either a line number or a /pattern/ searched for
+fn tag_line(text: &[String], address: &str) -> Option<usize> {
+    let mut chars = address.chars();
+    let delimiter = chars.next()?;
+    if delimiter != '/' && delimiter != '?' {
+        let digits: String = address.chars().take_while(char::is_ascii_digit).collect();
+        return digits.parse::<usize>().ok().map(|line| line.max(1) - 1);
+    }
+    let mut pattern = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => pattern.extend(chars.next()),
+            c if c == delimiter => break,
+            c => pattern.push(c),
         }
-        true
     }
+    let start = pattern.starts_with('^');
+    let end = pattern.ends_with('$') && pattern.len() > start as usize;
+    let pattern = &pattern[start as usize..pattern.len() - end as usize];
+    text.iter().position(|line| match (start, end) {
+        (true, true) => line == pattern,
+        (true, false) => line.starts_with(pattern),
+        (false, true) => line.ends_with(pattern),
+        (false, false) => line.contains(pattern),
+    })
+}
 
-    pub fn push_char(&mut self, c: char) {
-        if let Some(popup) = &mut self.popup {
-            popup.buf.push(c);
-        } else if let Some(prompt) = &mut self.prompt {
-            prompt.buf.push(c);
-        } else {
-            self.dirty = true;
-            if let Some(line) = self.text.get(self.cursor.1 as usize) {
-                if self.cursor.0 == 0 {
-                    let line = line.clone();
-                    let mut result = String::from(c);
-                    result.push_str(&line);
-                    self.text[self.cursor.1 as usize] = result;
-                    self.cursor_horizontal(1);
-                } else {
-                    let line = line.clone();
-                    let (left, right) = line.split_at(self.cursor.0 as usize);
-                    let mut result = String::from(left);
-                    result.push(c);
-                    result.push_str(right);
-                    self.text[self.cursor.1 as usize] = result;
-                    self.cursor_horizontal(1);
-                }
-            }
+#[derive(Copy, Clone, PartialEq)]
+enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
         }
     }
 
-    pub fn pop_char(&mut self) {
-        if let Some(popup) = &mut self.popup {
-            popup.buf.pop();
-        } else if let Some(prompt) = &mut self.prompt {
-            prompt.buf.pop();
-        } else {
-            self.dirty = true;
-            if self.highlight != self.cursor {
-                if self.highlight.1 == self.cursor.1 {
-                    // Single line selection
-                    let min_x = self.highlight.0.min(self.cursor.0);
-                    let max_x = self.highlight.0.max(self.cursor.0);
-                    let pop_count = max_x - min_x;
-                    if let Some(line) = self.text.get(self.cursor.1 as usize) {
-                        let line = line.clone();
-                        let (left, right) = line.split_at(max_x as usize);
-                        let mut result = String::from(left);
-                        for _ in 0..pop_count { result.pop(); }
-                        result.push_str(right);
-                        self.text[self.cursor.1 as usize] = result;
-                        self.cursor_horizontal(-(pop_count as i16));
-                    }
-                } else {
-                    // Multi line selection
-                    todo!();
-                }
-            } else {
-                let remove = if let Some(line) = self.text.get(self.cursor.1 as usize) {
-                    if self.cursor.0 == 0 {
-                        self.cursor.1 != 0
-                    } else {
-                        let line = line.clone();
-                        let (left, right) = line.split_at(self.cursor.0 as usize);
-                        let mut result = String::from(left);
-                        result.pop();
-                        result.push_str(right);
-                        self.text[self.cursor.1 as usize] = result;
-                        self.cursor_horizontal(-1);
-                        false
-                    }
-                } else {
-                    false
-                };
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Lf => "LF",
+            Self::CrLf => "CRLF",
+        }
+    }
+}
 
-                if remove {
-                    let cur = self.text.get(self.cursor.1 as usize).unwrap().clone();
-                    self.text.remove(self.cursor.1 as usize);
-                    self.cursor_vertical(-1);
-                    self.cursor_end_of_line();
-                    if let Some(line) = self.text.get_mut(self.cursor.1 as usize) {
-                        line.push_str(&cur);
-                    }
-                }
-            }
+// Splits text into lines and the ending of each, a last line without one has none.
+// Also returns the most common line ending and how many lines used the other one
+fn split_lines(s: &str) -> (Vec<String>, Vec<LineEnding>, LineEnding, usize) {
+    let mut lines = Vec::new();
+    let mut endings = Vec::new();
+    let mut rest = s;
+    while let Some(i) = rest.find('\n') {
+        match rest[..i].strip_suffix('\r') {
+            Some(line) => {
+                lines.push(line.to_string());
+                endings.push(LineEnding::CrLf);
+            },
+            None => {
+                lines.push(rest[..i].to_string());
+                endings.push(LineEnding::Lf);
+            },
         }
+        rest = &rest[i + 1..];
+    }
+    if !rest.is_empty() {
+        lines.push(rest.to_string());
+    }
+    let crlf = endings.iter().filter(|&&ending| ending == LineEnding::CrLf).count();
+    let lf = endings.len() - crlf;
+    if crlf > lf {
+        (lines, endings, LineEnding::CrLf, lf)
+    } else {
+        (lines, endings, LineEnding::Lf, crlf)
     }
+}
 
-    pub fn pop_char_del(&mut self) {
-        if self.prompt.is_none() && self.popup.is_none() {
-            self.dirty = true;
-            if self.highlight != self.cursor {
-                self.pop_char();
-            } else if let Some(line) = self.text.get(self.cursor.1 as usize) {
-                if self.cursor.0 as usize >= line.len() {
-                    return;
+// Byte range of the first match of `pattern` in `line`
+fn find_match(line: &str, pattern: &str, ignore_case: bool) -> Option<(usize, usize)> {
+    if !ignore_case {
+        return line.find(pattern).map(|x| (x, x + pattern.len()));
+    }
+    // Compared char by char, lowercasing can change how many bytes a char takes
+    let pattern: Vec<char> = pattern.chars().flat_map(char::to_lowercase).collect();
+    line.char_indices().find_map(|(x, _)| {
+        let mut rest = pattern.iter();
+        let mut end = x;
+        for c in line[x..].chars() {
+            if rest.as_slice().is_empty() {
+                break;
+            }
+            for lower in c.to_lowercase() {
+                if rest.next() != Some(&lower) {
+                    return None;
                 }
-                let line = line.clone();
-                let (left, right) = line.split_at(self.cursor.0 as usize);
-                let mut result = String::from(left);
-                result.push_str(&right[1..]);
-                self.text[self.cursor.1 as usize] = result;
             }
+            end += c.len_utf8();
         }
+        rest.as_slice().is_empty().then_some((x, end))
+    })
+}
+
+// Byte ranges of every match of `pattern` in `line`, none overlapping
+fn find_matches(line: &str, pattern: &str, ignore_case: bool) -> Vec<(usize, usize)> {
+    let mut matches = Vec::new();
+    if pattern.is_empty() {
+        return matches;
+    }
+    let mut from = 0;
+    while let Some((x, end)) = find_match(&line[from..], pattern, ignore_case) {
+        matches.push((from + x, from + end));
+        from += end;
+    }
+    matches
+}
+
+// Unified diff of two texts, compared line by line so a missing final newline doesn't show up as a change
+fn line_diff(old: &str, new: &str) -> String {
+    let old: String = old.lines().map(|l| format!("{}\n", l)).collect();
+    let new: String = new.lines().map(|l| format!("{}\n", l)).collect();
+    let diff = similar::TextDiff::from_lines(&old, &new);
+    diff.unified_diff().context_radius(2).iter_hunks().map(|hunk| hunk.to_string()).collect()
+}
+
+// Breaks lines longer than `width` chars, at the last space if there is one
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut rows = Vec::new();
+    for line in text.lines() {
+        let mut rest: Vec<char> = line.chars().collect();
+        while rest.len() > width {
+            let split = rest[..=width].iter().rposition(|&c| c == ' ').filter(|&i| i > 0).unwrap_or(width);
+            rows.push(rest[..split].iter().collect());
+            let skip = if rest[split] == ' ' { 1 } else { 0 };
+            rest.drain(..split + skip);
+        }
+        rows.push(rest.into_iter().collect());
+    }
+    rows
+}
+
+// The scroll closest to `scroll` that shows `row` in a view `rows` lines high
+fn scroll_to_row(scroll: usize, row: usize, rows: usize) -> usize {
+    let rows = rows.max(1);
+    if row < scroll {
+        row
+    } else if row - scroll >= rows {
+        row + 1 - rows
+    } else {
+        scroll
+    }
+}
+
+// Where to return to after a tag jump
+struct Jump {
+    path: String,
+    cursor: (usize, usize),
+    scroll: usize,
+}
+
+// Syntax named by a modeline like `# fox: syntax=python` or `# vim: set ft=python:` near the start or end of the file
+fn modeline_syntax(text: &[String]) -> Option<String> {
+    let head = text.iter().take(MODELINE_LINES);
+    let tail = text.iter().skip(text.len().saturating_sub(MODELINE_LINES).max(MODELINE_LINES));
+    head.chain(tail).find_map(|line| {
+        let (_, options) = ["fox:", "vim:", "vi:"].iter().find_map(|marker| line.split_once(marker))?;
+        options.split(|c: char| c.is_whitespace() || c == ':').find_map(|option| {
+            let (key, value) = option.split_once('=')?;
+            matches!(key, "syntax" | "syn" | "filetype" | "ft").then(|| value.to_string())
+        })
+    })
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// A file read from disk, with everything needed to start editing it
+struct LoadedFile {
+    path_expanded: String,
+    text: Vec<String>,
+    endings: Vec<LineEnding>,
+    line_ending: LineEnding,
+    bytes: Option<Vec<u8>>, // Raw contents of binary files
+    modified: Option<SystemTime>,
+    read_only: bool,
+    new_file: bool, // Doesn't exist yet, saving creates it
+    cursor: (usize, usize),
+    scroll: usize,
+    status: String,
+    syntax: SyntaxReference,
+}
+
+fn expand_path(filename: &str) -> String {
+    shellexpand::full(filename).map(|s| s.to_string()).unwrap_or(filename.to_string())
+}
+
+// Picks the syntax from a modeline, or else the file's extension or name
+fn detect_syntax(text: &[String], path: &Path) -> &'static SyntaxReference {
+    let ps = &carbon_dump::SYNTAX_SET;
+    let modeline = modeline_syntax(text).and_then(|name| ps.find_syntax_by_token(&name));
+    if let Some(syntax) = modeline {
+        syntax
+    } else if let Some(extension) = &path.extension().map(|s| s.to_str().expect("Unparsable extension!")) {
+        ps.find_syntax_by_extension(extension).unwrap_or_else(|| ps.find_syntax_plain_text())
+    } else if let Some(filename) = &path.file_name().map(|s| s.to_str().expect("Unparsable filename!")) {
+        ps.find_syntax_by_extension(filename).unwrap_or_else(|| ps.find_syntax_plain_text())
+    } else {
+        ps.find_syntax_plain_text()
+    }
+}
+
+fn load_file(filename: &str, config: &Config) -> Result<LoadedFile> {
+    let filename_expanded = expand_path(filename);
+    let path = Path::new(&filename_expanded);
+    if path.is_dir() {
+        return Err(std::io::Error::other(format!("{} is a directory", filename)));
+    }
+    let file_size = std::fs::metadata(path).map(|m| m.len()).ok();
+    let modified = modified_time(path);
+    let read_only = path.exists() && std::fs::OpenOptions::new().write(true).open(path).is_err();
+    let bytes = if path.exists() { // Perhaps try_exists is better here
+        std::fs::read(path)?
+    } else {
+        Vec::new()
+    };
+    // Anything that isn't valid UTF-8 or has NUL bytes in it is treated as binary
+    let ((mut text, endings, line_ending, mixed), bytes) = match String::from_utf8(bytes) {
+        Ok(s) if !s.contains('\0') => (split_lines(&s), None),
+        Ok(s) => ((Vec::new(), Vec::new(), LineEnding::Lf, 0), Some(s.into_bytes())),
+        Err(e) => ((Vec::new(), Vec::new(), LineEnding::Lf, 0), Some(e.into_bytes())),
+    };
+    if text.is_empty() {
+        text.push(String::new());
+    }
+    let mut status = file_size.map(|size| match bytes {
+        Some(_) => format!("Binary file, {}, showing read-only hex view", format_size(size)),
+        None => format!("{} lines, {}", text.len(), format_size(size)),
+    }).unwrap_or_else(|| String::from("New file"));
+    if mixed > 0 {
+        status.push_str(&format!(", mixed line endings, {} lines don't end in {} (F6 to convert)", mixed, line_ending.name()));
+    }
+    if config.editor.warn_mixed_indent {
+        let mixed = text.iter().filter(|line| {
+            let indent = leading_whitespace(line);
+            indent.contains(' ') && indent.contains('\t')
+        }).count();
+        if mixed > 0 {
+            status.push_str(&format!(", {} lines mix tabs and spaces", mixed));
+        }
+    }
+    let syntax = detect_syntax(&text, path);
+
+    let position = state::position(&position_key(path)).filter(|p| p.cursor.1 < text.len());
+    let (cursor, scroll) = position.map(|p| {
+        let x = p.cursor.0.min(line_len(&text[p.cursor.1]));
+        ((x, p.cursor.1), p.scroll.min(p.cursor.1))
+    }).unwrap_or(((0,0), 0));
+
+    Ok(LoadedFile {
+        path_expanded: filename_expanded,
+        text,
+        endings,
+        line_ending,
+        bytes,
+        modified,
+        read_only,
+        new_file: file_size.is_none(),
+        cursor,
+        scroll,
+        status,
+        syntax: syntax.clone(),
+    })
+}
+
+// load_file for the editor, saying what's going on while a large file is read
+fn load_file_in_terminal(filename: &str, config: &Config) -> Result<LoadedFile> {
+    let large = std::fs::metadata(expand_path(filename)).is_ok_and(|m| m.len() > LARGE_FILE_SIZE);
+    if large {
+        print!("Loading {}...", filename);
+        stdout().flush()?;
+    }
+    let file = load_file(filename, config);
+    if large {
+        print!("\r");
+        stdout().execute(Clear(ClearType::CurrentLine))?;
+    }
+    file
+}
+
+// Relative luminance of a color, 0 for black to 1 for white
+fn luminance(color: Color) -> f32 {
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    0.2126 * linear(color.r) + 0.7152 * linear(color.g) + 0.0722 * linear(color.b)
+}
+
+// Moves a color `amount` (0 to 1) of the way towards white if it's dark, or towards black if it's light
+fn shade_color(color: Color, amount: f32) -> Color {
+    let amount = amount.clamp(0.0, 1.0);
+    let dark = luminance(color) < 0.18; // Perceptually halfway between black and white
+    let shade = |c: u8| if dark {
+        (c as f32 + (255.0 - c as f32) * amount) as u8
+    } else {
+        (c as f32 * (1.0 - amount)) as u8
+    };
+    Color {
+        r: shade(color.r),
+        g: shade(color.g),
+        b: shade(color.b),
+        a: color.a,
+    }
+}
+
+// Parses a "#rrggbb" color
+fn parse_hex_color(s: &str) -> Option<Color> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(Color {
+        r: channel(0)?,
+        g: channel(2)?,
+        b: channel(4)?,
+        a: 255,
+    })
+}
+
+fn config_theme(config: &Config) -> &'static Theme {
+    let ts = &carbon_dump::THEME_SET;
+    ts.themes.get(&config.theme.name).unwrap_or_else(|| &ts.themes[&Config::default().theme.name]) // gruvbox-dark
+}
+
+pub struct Fox {
+    path_expanded: String,
+    path: String,
+    text: Vec<String>,
+    cursor: (usize, usize),
+    highlight: (usize, usize), // Selection anchor, the cursor is the end that moves
+    found: Option<((usize, usize), (usize, usize))>, // Selection made by the last search, drawn in the find colors while it stays selected
+    search: Option<String>, // Last search, its matches stay highlighted until Esc or an edit
+    ignore_case: bool, // Searches ignore case, toggled with alt-c
+    goal_column: Option<(usize, (usize, usize))>, // Column to aim for when moving vertically, as long as the cursor is still where we left it
+    scroll: usize,
+    hex: Option<Vec<u8>>, // Binary file shown as a read-only hex view instead of `text`
+    hex_cursor: usize,
+    modified: Option<SystemTime>, // When the file was last changed on disk, as far as we know
+    read_only: bool, // We aren't allowed to write to the file
+    new_file: bool,
+    swap_pending: bool, // The swap file is behind the buffer
+
+    terminal: bool, // We set the terminal up for editing, and put it back when dropped
+    alt_screen: bool,
+    dirty: bool,
+    verbatim: bool,
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    last_edit: Option<(EditKind, (usize, usize))>,
+    last_action: Option<Action>,
+    prompt: Option<Prompt>,
+    popup: Option<Prompt>,
+    jumps: Vec<Jump>, // Where tag jumps came from, most recent last
+    endings: Vec<LineEnding>, // How each line of `text` ends, the last one may not have an ending
+    line_ending: LineEnding, // Ending for new lines, the file's most common one
+    register: String, // Last copied text, kept for when the system clipboard can't be used
+    flash: bool, // Draw the header inverted for a moment, as a visual bell
+    replace_term: String, // What the replace prompt is replacing, while asking what with
+    replacing_all: bool, // Whether that goes for every match at once or one at a time
+    pending_path: String, // Where Save As writes or the file Open opens, while a prompt asks whether to go ahead
+    status: String,
+    lsp: Option<Lsp>,
+    diagnostics: Vec<Diagnostic>,
+
+    config: Config,
+    syntax: SyntaxReference,
+    theme: Theme,
+
+    bg: Color,
+    fg: Color,
+    gutter_bg: Color,
+    gutter_fg: Color,
+    highlight_bg: Color,
+    highlight_fg: Color,
+    find_bg: Color,
+    find_fg: Color,
+    header_bg: Color,
+}
+
+impl Fox {
+    pub fn new(filename: &str, alt_screen: bool) -> Result<Self> {
+        let mut config = config();
+        let alt_screen = alt_screen && config.editor.alt_screen;
+
+        let file = load_file_in_terminal(filename, &config)?;
+        config.apply_filetype(&file.syntax.name);
+
+        if alt_screen {
+            stdout().execute(EnterAlternateScreen)?;
+        }
+        // stdout().execute(cursor::SetCursorShape(cursor::CursorShape::Line))?;
+        enable_raw_mode()?;
+        stdout().execute(EnableMouseCapture)?;
+        // Lets terminals with the Kitty keyboard protocol report combinations like ctrl-Enter,
+        // others ignore the request and keep sending the legacy codes
+        let _ = stdout().execute(PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES));
+
+        let mut fox = Self::with_file(filename, file, config);
+        fox.alt_screen = alt_screen;
+        fox.terminal = true;
+        fox.start_lsp();
+        fox.touch_recent();
+        if fox.config.editor.swap_file {
+            let swap = fox.swap_path();
+            let swap_modified = modified_time(&swap);
+            if swap_modified.is_some() && (fox.modified.is_none() || swap_modified > fox.modified) {
+                fox.prompt(PromptType::RecoverSwap);
+            }
+        }
+        Ok(fox)
+    }
+
+    // The editor for a loaded file, leaving the terminal alone
+    fn with_file(filename: &str, file: LoadedFile, config: Config) -> Self {
+        let theme = config_theme(&config);
+        let bg = theme.settings.background.unwrap_or(Color::BLACK);
+        let fg = theme.settings.foreground.unwrap_or(Color::WHITE);
+        let shade = config.theme.shade / 100.0;
+        let gutter_bg = theme.settings.gutter.unwrap_or(shade_color(bg, shade));
+        let gutter_fg = theme.settings.gutter_foreground.unwrap_or(fg);
+        let highlight_bg = theme.settings.selection.unwrap_or(theme.settings.highlight.unwrap_or(theme.settings.line_highlight.unwrap_or(theme.settings.find_highlight.unwrap_or(shade_color(bg, shade * 4.0)))));
+        let highlight_fg = theme.settings.selection_foreground.unwrap_or(fg);
+        let find_bg = config.theme.find_bg.as_deref().and_then(parse_hex_color)
+            .or(theme.settings.find_highlight)
+            .unwrap_or(highlight_bg);
+        let find_fg = config.theme.find_fg.as_deref().and_then(parse_hex_color)
+            .or(theme.settings.find_highlight_foreground)
+            .unwrap_or(highlight_fg);
+        let header_bg = shade_color(bg, shade * 2.0);
+
+        Self {
+            path_expanded: file.path_expanded,
+            path: filename.to_string(),
+            text: file.text,
+            endings: file.endings,
+            line_ending: file.line_ending,
+            cursor: file.cursor,
+            highlight: file.cursor,
+            found: None,
+            search: None,
+            ignore_case: config.editor.ignore_case,
+            goal_column: None,
+            scroll: file.scroll,
+            hex: file.bytes,
+            hex_cursor: 0,
+            modified: file.modified,
+            read_only: file.read_only,
+            new_file: file.new_file,
+            swap_pending: false,
+
+            terminal: false,
+            alt_screen: false,
+            dirty: false,
+            verbatim: false,
+            undo: Vec::new(),
+            redo: Vec::new(),
+            last_edit: None,
+            last_action: None,
+            prompt: None,
+            popup: None,
+            jumps: Vec::new(),
+            replace_term: String::new(),
+            replacing_all: false,
+            pending_path: String::new(),
+            register: String::new(),
+            flash: false,
+            status: file.status,
+            lsp: None,
+            diagnostics: Vec::new(),
+
+            syntax: file.syntax,
+            theme: theme.clone(),
+            config,
+
+            bg,
+            fg,
+            gutter_bg,
+            gutter_fg,
+            highlight_bg,
+            highlight_fg,
+            find_bg,
+            find_fg,
+            header_bg,
+        }
+    }
+
+    // Where the buffer is backed up while it has unsaved changes, `.name.swp` next to the file
+    fn swap_path(&self) -> PathBuf {
+        let path = Path::new(&self.path_expanded);
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        path.with_file_name(format!(".{}.swp", name))
+    }
+
+    // Backs up unsaved changes to the swap file if they changed since the last time
+    pub fn write_swap(&mut self) {
+        if !self.config.editor.swap_file || !self.swap_pending || !self.dirty || self.hex.is_some() {
+            return;
+        }
+        self.swap_pending = false;
+        if let Err(e) = std::fs::write(self.swap_path(), self.saved_text()) {
+            self.status = format!("Could not write swap file: {}", e);
+        }
+    }
+
+    pub fn remove_swap(&self) {
+        if self.config.editor.swap_file {
+            let _ = std::fs::remove_file(self.swap_path());
+        }
+    }
+
+    fn recover_swap(&mut self) {
+        let text = match std::fs::read_to_string(self.swap_path()) {
+            Ok(text) => text,
+            Err(e) => {
+                self.status = format!("Could not read swap file: {}", e);
+                return;
+            },
+        };
+        self.edit(EditKind::Other, |fox| {
+            (fox.text, fox.endings, _, _) = split_lines(&text);
+            if fox.text.is_empty() {
+                fox.text.push(String::new());
+            }
+            fox.highlight = fox.cursor;
+            fox.clamp_cursor();
+        });
+        self.status = String::from("Recovered unsaved changes");
+    }
+
+    // Starts the language server configured for this file, if any
+    fn start_lsp(&mut self) {
+        self.lsp = None;
+        self.diagnostics.clear();
+        if !self.config.lsp.enabled || self.hex.is_some() {
+            return;
+        }
+        let path = Path::new(&self.path_expanded);
+        let command = path.extension().and_then(|e| e.to_str()).and_then(|e| self.config.lsp.servers.get(e));
+        if let Some(command) = command {
+            match Lsp::start(command, path, &self.syntax.name.to_lowercase()) {
+                Ok(lsp) => self.lsp = Some(lsp),
+                Err(e) => self.status = format!("Could not start language server: {}", e),
+            }
+        }
+    }
+
+    // Talks to the language server, returns whether the diagnostics changed
+    pub fn poll_lsp(&mut self) -> bool {
+        let text = &self.text;
+        match self.lsp.as_mut().and_then(|lsp| lsp.poll(|| text.join("\n"))) {
+            Some(diagnostics) => {
+                self.diagnostics = diagnostics;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // The most severe diagnostic on a line
+    fn diagnostic(&self, line: usize) -> Option<&Diagnostic> {
+        self.diagnostics.iter().filter(|d| d.line == line).min_by(|a, b| a.severity.partial_cmp(&b.severity).unwrap())
+    }
+
+    // Columns a row of text gets when long lines wrap, None when they don't
+    fn wrap_width(&self, terminal_width: u16) -> Option<usize> {
+        if !self.config.editor.wrap || self.hex.is_some() {
+            return None;
+        }
+        let scrollbar = self.config.editor.scrollbar as usize;
+        Some((terminal_width as usize).saturating_sub(self.gutter_width() + 2 + scrollbar).max(1))
+    }
+
+    // row_starts() of line `y` when wrapping at `wrap` columns
+    fn line_row_starts(&self, y: usize, wrap: usize) -> Vec<usize> {
+        self.text.get(y).map_or(vec![0], |line| row_starts(line, wrap, self.tab_width()))
+    }
+
+    // Draws `text` from column `col` of the line starting on `row`, following the line's row_starts() when wrapping
+    fn draw_span(&self, row: u16, col: usize, text: &str, (fg, bg): (Color, Color), rows: u16, starts: Option<&[usize]>) -> Result<()> {
+        use owo_colors::OwoColorize;
+
+        let gutter = self.gutter_width() + 2;
+        // Cut into the pieces drawn on each row
+        let mut pieces: Vec<((usize, usize), String)> = Vec::new();
+        let mut col = col;
+        for c in text.chars() {
+            let at = starts.map_or((0, col), |starts| wrapped_col(col, starts));
+            match pieces.last_mut() {
+                Some(((piece_row, _), piece)) if *piece_row == at.0 => piece.push(c),
+                _ => pieces.push((at, c.to_string())),
+            }
+            col += char_width(c);
+        }
+        for ((piece_row, piece_col), piece) in pieces {
+            let piece_row = row + piece_row as u16;
+            if piece_row > rows {
+                break;
+            }
+            stdout().execute(cursor::MoveTo((piece_col + gutter) as u16, piece_row))?;
+            print!("{}", piece.truecolor(fg.r, fg.g, fg.b).on_truecolor(bg.r, bg.g, bg.b));
+        }
+        Ok(())
+    }
+
+    pub fn redraw(&mut self) -> Result<()> {
+        self.draw()?;
+        if self.flash {
+            // Hold the visual bell just long enough to be seen
+            std::thread::sleep(Duration::from_millis(80));
+            self.flash = false;
+            self.draw()?;
+        }
+        Ok(())
+    }
+
+    fn draw(&mut self) -> Result<()> {
+        use owo_colors::OwoColorize;
+
+        stdout().execute(cursor::Hide)?;
+
+        let terminal_size = size()?;
+
+        // Header
+        stdout().execute(cursor::MoveTo(0,0))?;
+        let mut markers = String::new();
+        if self.new_file { markers.push_str(" [New]"); }
+        if self.read_only || self.hex.is_some() { markers.push_str(" [RO]"); }
+        if self.dirty { markers.push_str(" [+]"); }
+        // The markers are kept when the path has to be cut off
+        let mut filename: String = self.path.chars().take((terminal_size.0 as usize).saturating_sub(markers.len())).collect();
+        filename.push_str(&markers);
+        let len = filename.chars().count().min(terminal_size.0 as usize);
+        let offset = (terminal_size.0 as usize - len) / 2;
+        let (header_fg, header_bg) = if self.flash { (self.header_bg, self.fg) } else { (self.fg, self.header_bg) };
+        for _ in 0..offset {
+            print!("{}", " ".on_truecolor(header_bg.r,header_bg.g,header_bg.b));
+        }
+        stdout().execute(cursor::MoveTo(offset as u16,0))?;
+        print!("{}", filename.truecolor(header_fg.r, header_fg.g, header_fg.b).on_truecolor(header_bg.r,header_bg.g,header_bg.b));
+        for _ in offset + len..terminal_size.0 as usize {
+            print!("{}", " ".on_truecolor(header_bg.r,header_bg.g,header_bg.b));
+        }
+
+        // Content, dimmed while typing goes to a prompt or popup instead
+        let mut h = HighlightLines::new(&self.syntax, &self.theme);
+        let width = self.gutter_width();
+        let rows = self.text_rows(terminal_size.1);
+        let unfocused = self.prompt.is_some() || self.popup.is_some();
+        if unfocused {
+            stdout().execute(SetAttribute(Attribute::Dim))?;
+        }
+        // Lines on screen with the row each starts on, a wrapped line can take several
+        let wrap = self.wrap_width(terminal_size.0);
+        let mut visible: Vec<(usize, u16)> = Vec::new();
+        let mut i = 1;
+        while i <= rows {
+            let line_num = visible.len() + self.scroll + 1;
+            stdout().execute(cursor::MoveTo(0,i))?;
+            if self.hex.is_some() {
+                self.draw_hex_row(self.hex_screen_row(i), terminal_size.0)?;
+                i += 1;
+            } else if let Some(line) = self.text.get(line_num-1) {
+                visible.push((line_num-1, i));
+                // Diagnostic sign
+                let (sign, sign_color) = match self.diagnostic(line_num-1).map(|d| d.severity) {
+                    Some(Severity::Error) => ("●", (230, 60, 60)),
+                    Some(Severity::Warning) => ("●", (230, 180, 40)),
+                    Some(Severity::Information | Severity::Hint) => ("●", (80, 150, 230)),
+                    None => (" ", (self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b)),
+                };
+                print!("{}", sign.truecolor(sign_color.0, sign_color.1, sign_color.2).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+                print!("{}", format!("{: >width$} ", line_num, width=width).truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+
+                // let line = &line[..line.len().min(terminal_size.0 as usize - width - 2)];
+                let ranges: Vec<(Style, &str)> = h.highlight(line, &carbon_dump::SYNTAX_SET);
+                let ranges = expand_tabs(&ranges, self.tab_width(), self.gutter_bg, &self.config.markers, self.config.editor.indent_guides);
+                let segments = match wrap {
+                    Some(w) => wrap_ranges(&ranges, w),
+                    None => vec![ranges],
+                };
+                for (n, segment) in segments.iter().enumerate() {
+                    if i > rows {
+                        break;
+                    }
+                    // Rows the line continues on have an empty gutter
+                    if n > 0 {
+                        stdout().execute(cursor::MoveTo(0,i))?;
+                        print!("{}", format!(" {: >width$} ", "", width=width).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+                    }
+                    let ranges: Vec<(Style, &str)> = segment.iter().map(|(style, s)| (*style, s.as_str())).collect();
+                    let line = as_24_bit_terminal_escaped(&ranges[..], true);
+                    print!("{}", line);
+
+                    //Finish line
+                    for _ in cursor::position()?.0 .. terminal_size.0 { print!("{}", " ".on_truecolor(self.bg.r, self.bg.g, self.bg.b)); }
+                    i += 1;
+                }
+            } else {
+                print!("{}", format!(" {: >width$} ", "", width=width).truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+                print!("{}", self.config.markers.empty_line.truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.bg.r, self.bg.g, self.bg.b));
+                //Finish line
+                for _ in cursor::position()?.0 .. terminal_size.0 { print!("{}", " ".on_truecolor(self.bg.r, self.bg.g, self.bg.b)); }
+                i += 1;
+            }
+        }
+
+        // Search matches, the selection is drawn over them
+        if let Some(s) = self.search.as_ref().filter(|_| self.hex.is_none()) {
+            let ignore_case = self.ignores_case(s);
+            for &(y, row) in &visible {
+                let line = &self.text[y];
+                let starts = wrap.map(|w| row_starts(line, w, self.tab_width()));
+                for (x, end) in find_matches(line, s, ignore_case) {
+                    let start_col = advance_col(&line[..x], 0, self.tab_width());
+                    let text = expand_segment(&line[x..end], start_col, self.tab_width());
+                    self.draw_span(row, start_col, &text, (self.find_fg, self.find_bg), rows, starts.as_deref())?;
+                }
+            }
+        }
+
+        // Highlight
+        if self.highlight != self.cursor {
+            let (start, end) = self.selection();
+            let (fg, bg) = if self.found == Some((self.highlight, self.cursor)) {
+                (self.find_fg, self.find_bg)
+            } else {
+                (self.highlight_fg, self.highlight_bg)
+            };
+            for &(y, row) in visible.iter().filter(|&&(y, _)| (start.1..=end.1).contains(&y)) {
+                if let Some(line) = self.text.get(y) {
+                    let min_x = if y == start.1 { byte_index(line, start.0) } else { 0 };
+                    let max_x = if y == end.1 { byte_index(line, end.0) } else { line.len() };
+                    let start_col = advance_col(&line[..min_x], 0, self.tab_width());
+                    let mut text = expand_segment(&line[min_x..max_x], start_col, self.tab_width());
+                    // Show the selected line break
+                    if y != end.1 {
+                        text.push(' ');
+                    }
+                    let starts = wrap.map(|w| row_starts(line, w, self.tab_width()));
+                    self.draw_span(row, start_col, &text, (fg, bg), rows, starts.as_deref())?;
+                }
+            }
+        }
+
+        // Scrollbar
+        if let Some((thumb_start, thumb_len)) = self.scrollbar_thumb(rows) {
+            for i in 0..rows {
+                stdout().execute(cursor::MoveTo(terminal_size.0 - 1, i + 1))?;
+                let color = if (thumb_start..thumb_start + thumb_len).contains(&i) { self.highlight_bg } else { self.gutter_bg };
+                print!("{}", " ".on_truecolor(color.r, color.g, color.b));
+            }
+        }
+
+        if unfocused {
+            stdout().execute(SetAttribute(Attribute::NormalIntensity))?;
+        }
+
+        // Separator between the text and the footer
+        if self.config.editor.footer_separator {
+            stdout().execute(cursor::MoveTo(0,rows+1))?;
+            print!("{}", "─".repeat(terminal_size.0 as usize).truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
+        }
+
+        // Footer
+        stdout().execute(cursor::MoveTo(0,terminal_size.1))?;
+        for _ in 0..terminal_size.0 { print!("{}", " ".on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b)); }
+        stdout().execute(cursor::MoveTo(0,terminal_size.1))?;
+
+        // Status/prompt
+        let mut prompt_end = 0;
+        if let Some(prompt) = &self.prompt {
+            // The label is picked out while the prompt has focus, a popup on top of it takes that away
+            let label = format!("{}: ", prompt.prompt.text());
+            if self.popup.is_some() {
+                print!("{}", label.truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
+            } else {
+                print!("{}", label.truecolor(self.highlight_fg.r, self.highlight_fg.g, self.highlight_fg.b).on_truecolor(self.highlight_bg.r,self.highlight_bg.g,self.highlight_bg.b));
+            }
+            print!("{}", prompt.buf.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
+            prompt_end = (label.chars().count() + prompt.buf.chars().map(char_width).sum::<usize>()).min(terminal_size.0 as usize - 1);
+            if !prompt.description.is_empty() {
+                print!("{}", format!("  ({})", prompt.description).truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
+            }
+        } else {
+            if self.status.is_empty() {
+                if let Some(diagnostic) = self.diagnostic(self.cursor.1) {
+                    self.status = diagnostic.message.lines().next().unwrap_or_default().to_string();
+                }
+            }
+            // Long messages would wrap and scroll the whole screen
+            let status: String = self.status.chars().take(terminal_size.0 as usize - 1).collect();
+            print!("{}", status.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
+            self.status = String::new();
+        }
+
+        // Cursor location
+        let footer_loc = if self.hex.is_some() {
+            format!("{:#x}", self.hex_cursor)
+        } else if self.highlight != self.cursor {
+            let (start, end) = self.selection();
+            let selected = self.get_selection().chars().count();
+            if start.1 == end.1 {
+                format!("{}:{} ({} sel)", self.cursor.0+1, self.cursor.1+1, selected)
+            } else {
+                format!("{}:{} ({} sel, {} lines)", self.cursor.0+1, self.cursor.1+1, selected, end.1 - start.1 + 1)
+            }
+        } else {
+            format!("{}:{}", self.cursor.0+1, self.cursor.1+1)
+        };
+        stdout().execute(cursor::MoveTo(terminal_size.0-footer_loc.len() as u16,terminal_size.1))?;
+        print!("{}", footer_loc.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.header_bg.r,self.header_bg.g,self.header_bg.b));
+
+        // Popup rendering
+        let mut popup_end = None;
+        if let Some(popup) = &self.popup {
+            let (x, y, w, h) = self.popup_rect(terminal_size);
+            for i in 0..h {
+                stdout().execute(cursor::MoveTo(x,y+i))?;
+                for _ in 0..w {
+                    print!("{}", " ".on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+                }
+            }
+
+            let max_text_width = (w - 2) as usize;
+            let title = if popup.buf.is_empty() { popup.prompt.text().to_string() } else { format!("{} {}", popup.prompt.text(), popup.buf) };
+            // Keep the end of the title, that's where the typing happens
+            let len = title.chars().count().min(max_text_width);
+            let title: String = title.chars().skip(title.chars().count() - len).collect();
+            let offset = (max_text_width - len) / 2;
+            stdout().execute(cursor::MoveTo(x+1+offset as u16,y+1))?;
+            print!("{}", title.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+            popup_end = Some((x + 1 + (offset + len).min(max_text_width) as u16, y + 1));
+
+            let description = wrap_text(&popup.description.replace('\t', "    "), max_text_width);
+            let visible = h.saturating_sub(4) as usize;
+            for (i, line) in description.iter().skip(popup.scroll).enumerate().take(visible) {
+                stdout().execute(cursor::MoveTo(x+1,y+3+i as u16))?;
+                print!("{}", line.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+            }
+            // Hints that there's more to scroll to, in the margins above and below the text
+            for (more, shown, row) in [("more ↑", popup.scroll > 0, y + 2), ("more ↓", popup.scroll + visible < description.len(), y + h - 1)] {
+                if shown && row > y + 1 && w > 8 {
+                    stdout().execute(cursor::MoveTo(x + w - 8, row))?;
+                    print!("{}", more.truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+                }
+            }
+        }
+
+        // Move cursor to show typing location
+        if let Some((col, row)) = popup_end {
+            stdout().execute(cursor::MoveTo(col, row))?;
+            stdout().execute(cursor::Show)?;
+            stdout().flush()?;
+            return Ok(());
+        }
+        if self.prompt.is_some() {
+            stdout().execute(cursor::MoveTo(prompt_end as u16, terminal_size.1))?;
+            stdout().execute(cursor::Show)?;
+            stdout().flush()?;
+            return Ok(());
+        }
+        if self.hex.is_some() {
+            let row = self.hex_cursor / HEX_ROW;
+            if row < self.scroll || row - self.scroll >= rows as usize {
+                stdout().execute(cursor::Hide)?;
+            } else {
+                let col = HEX_OFFSET_WIDTH + 2 + hex_column(self.hex_cursor % HEX_ROW);
+                stdout().execute(cursor::MoveTo(col as u16, (row - self.scroll) as u16 + 1))?;
+                stdout().execute(cursor::Show)?;
+            }
+            stdout().flush()?;
+            return Ok(());
+        }
+        let col = advance_col(&self.text[self.cursor.1][..self.cursor_index()], 0, self.tab_width());
+        let (cpos_y, col) = match visible.iter().find(|&&(y, _)| y == self.cursor.1) {
+            Some(&(_, row)) => match wrap {
+                Some(w) => {
+                    let (cursor_row, col) = self.cursor_row_col(w);
+                    (row + cursor_row as u16, col)
+                },
+                None => (row, col),
+            },
+            None => (rows + 1, col),
+        };
+        if cpos_y > rows {
+            stdout().execute(cursor::Hide)?;
+        } else {
+            if self.highlight == self.cursor { stdout().execute(cursor::Show)?; } else { stdout().execute(cursor::Hide)?; }
+            stdout().execute(cursor::MoveTo((col + width + 2) as u16, cpos_y))?;
+        }
+
+        stdout().flush()?;
+        Ok(())
+    }
+
+    // First row and length of the scrollbar's thumb, if there's a scrollbar
+    fn scrollbar_thumb(&self, rows: u16) -> Option<(u16, u16)> {
+        let total = self.text.len();
+        if !self.config.editor.scrollbar || self.hex.is_some() || total <= rows as usize || rows == 0 {
+            return None;
+        }
+        let len = (rows as usize * rows as usize / total).max(1) as u16;
+        let start = (self.scroll * rows as usize / total) as u16;
+        Some((start.min(rows - len), len))
+    }
+
+    // Jumps to the part of the file a click on the scrollbar points at, returns whether the click was on it
+    pub fn scrollbar_click(&mut self, column: u16, row: u16) -> bool {
+        let (width, height) = terminal_size();
+        let rows = self.text_rows(height);
+        if self.popup.is_some() || self.scrollbar_thumb(rows).is_none() || column != width - 1 || row == 0 || row > rows {
+            return false;
+        }
+        let last = self.text.len() - 1;
+        let line = (row as usize - 1) * last / (rows as usize - 1).max(1);
+        self.go_to_line(line);
+        // Put the line where it was clicked, so dragging moves the view smoothly
+        self.scroll = self.cursor.1.saturating_sub(row as usize - 1);
+        true
+    }
+
+    // Where the popup goes on the screen, as x, y, width and height
+    fn popup_rect(&self, terminal_size: (u16, u16)) -> (u16, u16, u16, u16) {
+        // Sized relative to the terminal, but never smaller than a title row with a margin around it
+        let size = (self.config.editor.popup_size / 100.0).clamp(0.0, 1.0);
+        let w = ((terminal_size.0 as f32 * size) as u16).max(10).min(terminal_size.0);
+        let h = ((terminal_size.1 as f32 * size) as u16).max(3).min(terminal_size.1);
+        ((terminal_size.0 - w) / 2, (terminal_size.1 - h) / 2, w, h)
+    }
+
+    // Scrolls the popup's text by `rows`, as far as there is text to show
+    pub fn scroll_popup(&mut self, rows: isize) {
+        let terminal_size = terminal_size();
+        let (_, _, w, h) = self.popup_rect(terminal_size);
+        if let Some(popup) = &mut self.popup {
+            let total = wrap_text(&popup.description.replace('\t', "    "), w.saturating_sub(2) as usize).len();
+            let max = total.saturating_sub(h.saturating_sub(4) as usize);
+            popup.scroll = popup.scroll.saturating_add_signed(rows).min(max);
+        }
+    }
+
+    // Rows the popup's text moves by for PageUp/PageDown
+    pub fn popup_page(&self) -> isize {
+        let terminal_size = terminal_size();
+        let (_, _, _, h) = self.popup_rect(terminal_size);
+        h.saturating_sub(4).max(1) as isize
+    }
+
+    // The hex view row shown on screen row `i`, which counts from 1 below the header
+    fn hex_screen_row(&self, i: u16) -> usize {
+        self.scroll + i as usize - 1
+    }
+
+    fn draw_hex_row(&self, row: usize, width: u16) -> Result<()> {
+        use owo_colors::OwoColorize;
+
+        let bytes = self.hex.as_deref().unwrap_or_default();
+        let start = row * HEX_ROW;
+        if start >= bytes.len().max(1) {
+            print!("{}", " ".repeat(HEX_OFFSET_WIDTH + 2).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+            print!("{}", self.config.markers.empty_line.truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.bg.r, self.bg.g, self.bg.b));
+        } else {
+            let row_bytes = &bytes[start..(start + HEX_ROW).min(bytes.len())];
+            print!("{}", format!(" {:0>w$x} ", start, w=HEX_OFFSET_WIDTH).truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.gutter_bg.r, self.gutter_bg.g, self.gutter_bg.b));
+            let mut line = String::new();
+            for i in 0..HEX_ROW {
+                match row_bytes.get(i) {
+                    Some(b) => line.push_str(&format!("{:02x} ", b)),
+                    None => line.push_str("   "),
+                }
+                if i == HEX_ROW / 2 - 1 {
+                    line.push(' ');
+                }
+            }
+            print!("{}", line.truecolor(self.fg.r, self.fg.g, self.fg.b).on_truecolor(self.bg.r, self.bg.g, self.bg.b));
+            print!("{}", " ".on_truecolor(self.bg.r, self.bg.g, self.bg.b));
+            for (i, b) in row_bytes.iter().enumerate() {
+                let c = if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' };
+                if start + i == self.hex_cursor {
+                    print!("{}", c.truecolor(self.highlight_fg.r, self.highlight_fg.g, self.highlight_fg.b).on_truecolor(self.highlight_bg.r, self.highlight_bg.g, self.highlight_bg.b));
+                } else {
+                    print!("{}", c.truecolor(self.gutter_fg.r, self.gutter_fg.g, self.gutter_fg.b).on_truecolor(self.bg.r, self.bg.g, self.bg.b));
+                }
+            }
+        }
+        //Finish line
+        for _ in cursor::position()?.0 .. width { print!("{}", " ".on_truecolor(self.bg.r, self.bg.g, self.bg.b)); }
+        Ok(())
+    }
+
+    // Moves the hex view cursor by `i` bytes
+    pub fn hex_move(&mut self, i: isize) {
+        let len = self.hex.as_ref().map_or(0, Vec::len);
+        self.hex_cursor = self.hex_cursor.saturating_add_signed(i).min(len.saturating_sub(1));
+        self.ensure_cursor_visible();
+    }
+
+    // How many rows of a terminal `height` rows high show text, the rest being header, footer and separator
+    fn text_rows(&self, height: u16) -> u16 {
+        let chrome = if self.config.editor.footer_separator { 3 } else { 2 };
+        height.saturating_sub(chrome)
+    }
+
+    // Lets the user know something couldn't be done, by flashing the header or ringing the terminal's bell
+    fn bell(&mut self) {
+        if self.config.editor.visual_bell {
+            self.flash = true;
+        } else {
+            print!("\x07");
+        }
+    }
+
+    // Pins the cursor and selection anchor to where there's text, and scrolls to the cursor
+    fn clamp_cursor(&mut self) {
+        if self.text.is_empty() {
+            self.text.push(String::new());
+        }
+        self.endings.truncate(self.text.len());
+        let last = self.text.len() - 1;
+        for pos in [&mut self.cursor, &mut self.highlight] {
+            pos.1 = pos.1.min(last);
+            pos.0 = pos.0.min(line_len(&self.text[pos.1]));
+        }
+        self.ensure_cursor_visible();
+    }
+
+    // Where the cursor is in its line's bytes
+    fn cursor_index(&self) -> usize {
+        byte_index(&self.text[self.cursor.1], self.cursor.0)
+    }
+
+    // Scrolls just enough to have the cursor's row on screen
+    fn ensure_cursor_visible(&mut self) {
+        let (width, height) = terminal_size();
+        self.ensure_cursor_visible_in(width, height);
+    }
+
+    // ensure_cursor_visible on a terminal of the given size
+    fn ensure_cursor_visible_in(&mut self, width: u16, height: u16) {
+        let row = if self.hex.is_some() { self.hex_cursor / HEX_ROW } else { self.cursor.1 };
+        let rows = self.text_rows(height) as usize;
+        self.scroll = scroll_to_row(self.scroll, row, rows);
+        // Wrapped lines above take more than a row each, scroll on until the cursor's row fits too
+        if let Some(w) = self.wrap_width(width).filter(|_| self.cursor.1 < self.text.len()) {
+            let cursor_row = self.cursor_row_col(w).0;
+            while self.scroll < self.cursor.1 && (self.scroll..self.cursor.1).map(|y| self.line_row_starts(y, w).len()).sum::<usize>() + cursor_row >= rows {
+                self.scroll += 1;
+            }
+        }
+    }
+
+    pub fn remember_position(&self) {
+        state::remember_position(&position_key(Path::new(&self.path_expanded)), Position {
+            cursor: self.cursor,
+            scroll: self.scroll,
+        });
+    }
+
+    // Puts the file on the recent files list, only once it exists so unsaved new files don't end up there
+    fn touch_recent(&self) {
+        if let Ok(path) = std::fs::canonicalize(&self.path_expanded) {
+            state::touch_recent(&path.display().to_string());
+        }
+    }
+
+    pub fn prompt(&mut self, prompt: PromptType) {
+        self.prompt = Some(Prompt {
+            prompt,
+            buf: String::new(),
+            description: String::new(),
+            scroll: 0,
+        });
+    }
+
+    pub fn popup(&mut self, popup: PromptType) {
+        self.popup_with(popup, popup.description());
+    }
+
+    // Details on how the file is being handled, for the info popup
+    fn info(&self) -> String {
+        let path = std::fs::canonicalize(&self.path_expanded).map(|p| p.display().to_string()).unwrap_or_else(|_| self.path_expanded.clone());
+        let mut theme = self.config.theme.name.clone();
+        if !carbon_dump::THEME_SET.themes.contains_key(&theme) {
+            theme.push_str(&format!(" (not found, using {})", Config::default().theme.name));
+        }
+        let indent = if self.config.editor.soft_tabs { "spaces" } else { "tabs" };
+        let encoding = if self.hex.is_some() { "binary" } else { "UTF-8" };
+        format!("Path: {}\nSyntax: {}\nTheme: {}\nIndentation: {}, width {}\nLine endings: {}\nEncoding: {}",
+            path, self.syntax.name, theme, indent, self.tab_width(), self.line_ending.name(), encoding)
+    }
+
+    // Opens a popup showing `description` instead of the popup type's own
+    fn popup_with(&mut self, popup: PromptType, description: String) {
+        self.popup = Some(Prompt {
+            prompt: popup,
+            buf: String::new(),
+            description,
+            scroll: 0,
+        });
+    }
+
+    // The text as it's written to disk, each line with its own ending
+    fn saved_text(&self) -> String {
+        let mut text = String::new();
+        for (i, line) in self.text.iter().enumerate() {
+            text.push_str(line);
+            if let Some(ending) = self.endings.get(i) {
+                text.push_str(ending.as_str());
+            }
+        }
+        text
+    }
+
+    // A line was added at `y`, its line break ends like the one of the line before it
+    fn insert_ending(&mut self, y: usize) {
+        let i = y.saturating_sub(1).min(self.endings.len());
+        let ending = self.endings.get(i).copied().unwrap_or(self.line_ending);
+        self.endings.insert(i, ending);
+    }
+
+    // The line break after line `y` is gone, joining it with the next line or taking the line with it
+    fn remove_ending(&mut self, y: usize) {
+        if y < self.endings.len() {
+            self.endings.remove(y);
+        }
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "file is read-only, save it elsewhere with ctrl-shift-s"));
+        }
+        write_file(Path::new(&self.path_expanded), &self.saved_text(), self.config.editor.fsync_on_save)?;
+        self.modified = modified_time(Path::new(&self.path_expanded));
+        self.touch_recent();
+        self.dirty = false;
+        self.new_file = false;
+        self.remove_swap();
+        self.status = String::from("Saved!");
+        Ok(())
+    }
+
+    // Saves to `filename` and goes on editing the file there
+    pub fn save_as(&mut self, filename: &str) {
+        let path_expanded = expand_path(filename);
+        if let Err(e) = write_file(Path::new(&path_expanded), &self.saved_text(), self.config.editor.fsync_on_save) {
+            self.status = format!("Could not save: {}", e);
+            return;
+        }
+        self.remember_position();
+        self.remove_swap();
+        self.path = filename.to_string();
+        self.path_expanded = path_expanded;
+        self.modified = modified_time(Path::new(&self.path_expanded));
+        self.read_only = false;
+        self.new_file = false;
+        self.dirty = false;
+        // The new name can mean another syntax, and other settings with it
+        self.syntax = detect_syntax(&self.text, Path::new(&self.path_expanded)).clone();
+        let mut config = config();
+        config.apply_filetype(&self.syntax.name);
+        self.config = config;
+        self.start_lsp();
+        self.touch_recent();
+        self.status = format!("Saved as {}", filename);
+    }
+
+    // Smartcase: only searches with capitals in them care about case
+    fn ignores_case(&self, s: &str) -> bool {
+        self.ignore_case || (self.config.editor.smartcase && !s.chars().any(char::is_uppercase))
+    }
+
+    pub fn toggle_ignore_case(&mut self) {
+        self.ignore_case = !self.ignore_case;
+        let message = if self.ignore_case { "Ignoring case" } else { "Matching case" };
+        self.update_prompt();
+        match &mut self.prompt {
+            Some(prompt) if matches!(prompt.prompt, PromptType::Find) => prompt.description = String::from(message),
+            _ => self.status = String::from(message),
+        }
+    }
+
+    // Finds the first match at or after `start`, selecting it
+    fn find_from(&mut self, s: &str, start: (usize, usize)) -> bool {
+        let ignore_case = self.ignores_case(s);
+        for i in start.1..self.text.len() {
+            if let Some(line) = self.text.get(i) {
+                let from = if i == start.1 { byte_index(line, start.0) } else { 0 };
+                if let Some((x, end)) = find_match(&line[from..], s, ignore_case) {
+                    self.highlight.0 = char_col(line, from + x);
+                    self.cursor.0 = char_col(line, from + end);
+                    self.cursor.1 = i;
+                    self.highlight.1 = self.cursor.1;
+                    self.found = Some((self.highlight, self.cursor));
+                    self.search = Some(s.to_string());
+                    self.clamp_cursor();
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    fn count_matches(&self, s: &str) -> usize {
+        let ignore_case = self.ignores_case(s);
+        self.text.iter().map(|line| find_matches(line, s, ignore_case).len()).sum()
+    }
+
+    // Keeps the prompt's extra info in line with what's typed into it
+    fn update_prompt(&mut self) {
+        let count = match &self.prompt {
+            Some(prompt) if matches!(prompt.prompt, PromptType::Replace | PromptType::ReplaceAll) && !prompt.buf.is_empty() => Some(self.count_matches(&prompt.buf)),
+            _ => None,
+        };
+        if let Some(prompt) = &mut self.prompt {
+            prompt.description = match count {
+                Some(1) => String::from("1 match"),
+                Some(n) => format!("{} matches", n),
+                None => String::new(),
+            };
+        }
+    }
+
+    // Replaces the selected match of `s`, or the next one after the cursor, then selects the one after it.
+    // Returns whether there was one to replace
+    pub fn replace_next(&mut self, s: &str, with: &str) -> bool {
+        let start = self.selection().0;
+        if !(self.find_from(s, start) || self.find_from(s, (0, 0))) {
+            return false;
+        }
+        self.edit(EditKind::Other, |fox| {
+            fox.delete_selection();
+            fox.insert_str(with);
+        });
+        // Carries on after the replacement, so one containing `s` isn't replaced again
+        if !self.find_from(s, self.cursor) {
+            self.find_from(s, (0, 0));
+        }
+        true
+    }
+
+    // Replaces every occurrence of `s`, returns how many there were
+    pub fn replace_all(&mut self, s: &str, with: &str) -> usize {
+        let count = self.count_matches(s);
+        if count > 0 {
+            let ignore_case = self.ignores_case(s);
+            self.edit(EditKind::Other, |fox| {
+                for line in fox.text.iter_mut() {
+                    // Back to front so the earlier ranges stay where they are
+                    for (x, end) in find_matches(line, s, ignore_case).into_iter().rev() {
+                        line.replace_range(x..end, with);
+                    }
+                }
+                fox.highlight = fox.cursor;
+                fox.clamp_cursor();
+            });
+        }
+        count
+    }
+
+    // Saves with `line_ending` from now on, answering the convert prompt
+    pub fn convert_line_endings(&mut self, answer: &str) {
+        let line_ending = match answer.trim().to_lowercase().as_str() {
+            "lf" | "unix" => LineEnding::Lf,
+            "crlf" | "dos" | "windows" => LineEnding::CrLf,
+            _ => {
+                self.status = String::from("Unknown line ending, use lf or crlf");
+                return;
+            },
+        };
+        if line_ending != self.line_ending || self.endings.iter().any(|&ending| ending != line_ending) {
+            self.line_ending = line_ending;
+            self.endings.fill(line_ending);
+            self.dirty = true;
+        }
+        self.status = format!("Line endings will be saved as {}", line_ending.name());
+    }
+
+    pub fn find_next(&mut self, s: &str) -> bool {
+        self.find_from(s, self.cursor) || self.find_from(s, (0, 0))
+    }
+
+    // Finds the last match starting before `before`, selecting it
+    fn find_before(&mut self, s: &str, before: (usize, usize)) -> bool {
+        let ignore_case = self.ignores_case(s);
+        for i in (0..=before.1.min(self.text.len() - 1)).rev() {
+            let line = &self.text[i];
+            let limit = if i == before.1 { byte_index(line, before.0) } else { line.len() };
+            if let Some((x, end)) = find_matches(line, s, ignore_case).into_iter().take_while(|&(x, _)| x < limit).last() {
+                self.highlight = (char_col(line, x), i);
+                self.cursor = (char_col(line, end), i);
+                self.found = Some((self.highlight, self.cursor));
+                self.search = Some(s.to_string());
+                self.clamp_cursor();
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn find_prev(&mut self, s: &str) -> bool {
+        let last = self.text.len() - 1;
+        self.find_before(s, self.selection().0) || self.find_before(s, (line_len(&self.text[last]), last))
+    }
+
+    // Where the selected match of `s` is among all of them, as "N of M"
+    fn match_position(&self, s: &str) -> String {
+        let ignore_case = self.ignores_case(s);
+        let start = self.selection().0;
+        let mut before = 0;
+        let mut total = 0;
+        for (y, line) in self.text.iter().enumerate() {
+            let matches = find_matches(line, s, ignore_case);
+            if y < start.1 {
+                before += matches.len();
+            } else if y == start.1 {
+                let x = byte_index(line, start.0);
+                before += matches.iter().filter(|&&(mx, _)| mx < x).count();
+            }
+            total += matches.len();
+        }
+        format!("{} of {}", before + 1, total)
+    }
+
+    // Goes back a match, searching for what's typed into the search prompt or else the last search
+    pub fn find_previous(&mut self) {
+        let query = match &self.prompt {
+            Some(prompt) if matches!(prompt.prompt, PromptType::Find) => prompt.buf.clone(),
+            _ => self.search.clone().unwrap_or_default(),
+        };
+        if query.is_empty() {
+            self.prompt(PromptType::Find);
+            return;
+        }
+        let found = self.find_prev(&query);
+        let message = if found { self.match_position(&query) } else { String::from("Could not find string!") };
+        match &mut self.prompt {
+            Some(prompt) if matches!(prompt.prompt, PromptType::Find) => prompt.description = message,
+            _ => self.status = message,
+        }
+        if !found {
+            self.bell();
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            text: self.text.clone(),
+            endings: self.endings.clone(),
+            cursor: self.cursor,
+            highlight: self.highlight,
+            scroll: self.scroll,
+        }
+    }
+
+    // Lets everything that follows the text know it changed
+    fn text_changed(&mut self) {
+        self.swap_pending = true;
+        if let Some(lsp) = &mut self.lsp {
+            lsp.text_changed();
+        }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.text = snapshot.text;
+        self.endings = snapshot.endings;
+        self.cursor = snapshot.cursor;
+        self.highlight = snapshot.highlight;
+        self.scroll = snapshot.scroll;
+        self.text_changed();
+        self.ensure_cursor_visible();
+    }
+
+    // Saves the current state as an undo step, dropping the oldest ones past the limit
+    fn push_undo(&mut self, snapshot: Snapshot) {
+        self.undo.push(snapshot);
+        let excess = self.undo.len().saturating_sub(self.config.editor.undo_limit);
+        self.undo.drain(..excess);
+    }
+
+    // Runs `f` as an undoable edit, merging it into the previous undo step if it continues it
+    fn edit(&mut self, kind: EditKind, f: impl FnOnce(&mut Self)) {
+        let merge = match self.last_edit {
+            Some((last, pos)) if pos == self.cursor && self.highlight == self.cursor => match kind {
+                EditKind::Word => matches!(last, EditKind::Word | EditKind::Space),
+                EditKind::Space => last == EditKind::Space,
+                EditKind::Delete => last == EditKind::Delete,
+                EditKind::Other => false,
+            },
+            _ => false,
+        };
+        let before = (!merge).then(|| self.snapshot());
+        f(self);
+        if let Some(before) = before {
+            // Edits that didn't change anything, like backspace at the start of the file, leave no trace
+            if before.text == self.text {
+                self.ensure_cursor_visible();
+                return;
+            }
+            self.push_undo(before);
+            self.redo.clear();
+        }
+        self.dirty = true;
+        self.search = None;
+        self.last_edit = Some((kind, self.cursor));
+        self.text_changed();
+        self.ensure_cursor_visible();
     }
 
-    pub fn enter(&mut self) {
-        if let Some(line) = self.text.get(self.cursor.1 as usize) {
-            if self.cursor.0 as usize >= line.len() {
-                self.text.insert(self.cursor.1 as usize + 1, String::new());
-                self.cursor_vertical(1);
-                self.cursor_start_of_line();
+    // Whether the next edit picks up where the previous one left off
+    fn continues_edit(&self) -> bool {
+        self.highlight == self.cursor && self.last_edit.is_some_and(|(_, pos)| pos == self.cursor)
+    }
+
+    fn record_insert(&mut self, c: char, continues: bool) {
+        match &mut self.last_action {
+            Some(Action::Insert(text)) if continues => text.push(c),
+            _ => self.last_action = Some(Action::Insert(c.to_string())),
+        }
+    }
+
+    pub fn repeat_edit(&mut self) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            return;
+        }
+        match self.last_action.clone() {
+            Some(Action::Insert(text)) => self.edit(EditKind::Other, |fox| {
+                for c in text.chars() {
+                    if c == '\n' { fox.new_line(); } else { fox.insert_char(c); }
+                }
+            }),
+            Some(Action::Backspace(n)) => self.edit(EditKind::Other, |fox| (0..n).for_each(|_| fox.backspace())),
+            Some(Action::Delete(n)) => self.edit(EditKind::Other, |fox| (0..n).for_each(|_| fox.delete())),
+            None => {
+                self.status = String::from("Nothing to repeat");
+                self.bell();
+            },
+        }
+        self.last_edit = None;
+    }
+
+    pub fn undo(&mut self) {
+        if let Some(snapshot) = self.undo.pop() {
+            self.redo.push(self.snapshot());
+            self.restore(snapshot);
+            self.dirty = true;
+            self.last_edit = None;
+        } else {
+            self.status = String::from("Nothing to undo");
+            self.bell();
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some(snapshot) = self.redo.pop() {
+            self.push_undo(self.snapshot());
+            self.restore(snapshot);
+            self.dirty = true;
+            self.last_edit = None;
+        } else {
+            self.status = String::from("Nothing to redo");
+            self.bell();
+        }
+    }
+
+    pub fn push_char(&mut self, c: char) {
+        self.type_char(c, false);
+    }
+
+    // Inserts the character as is, skipping smart indentation
+    pub fn push_char_literal(&mut self, c: char) {
+        self.type_char(c, true);
+    }
+
+    fn type_char(&mut self, c: char, literal: bool) {
+        if let Some(popup) = &mut self.popup {
+            popup.buf.push(c);
+        } else if let Some(prompt) = &mut self.prompt {
+            prompt.buf.push(c);
+            self.update_prompt();
+        } else if let Some(close) = closing_pair(c).filter(|_| !literal && self.config.editor.auto_pairs && self.highlight != self.cursor) {
+            self.edit(EditKind::Other, |fox| fox.wrap_selection(c, close));
+        } else {
+            let kind = if c.is_whitespace() { EditKind::Space } else { EditKind::Word };
+            let continues = self.continues_edit();
+            self.edit(kind, |fox| if literal { fox.insert_literal(c) } else { fox.insert_char(c) });
+            self.record_insert(c, continues);
+        }
+    }
+
+    fn insert_char(&mut self, c: char) {
+        if self.config.editor.auto_pairs {
+            let line = &self.text[self.cursor.1];
+            let x = self.cursor_index();
+            let prev = line[..x].chars().next_back();
+            let next = line[x..].chars().next();
+            if next == Some(c) && matches!(c, ')' | ']' | '}' | '"' | '\'' | '`') {
+                // Type over the closing character
+                self.cursor.0 += 1;
+                self.highlight = self.cursor;
+                return;
+            }
+            if let Some(close) = closing_pair(c) {
+                let quote = close == c;
+                let open = next.is_none_or(|n| n.is_whitespace() || matches!(n, ')' | ']' | '}'))
+                    && !(quote && prev.is_some_and(is_word_char));
+                if open {
+                    self.insert_literal(c);
+                    self.insert_literal(close);
+                    self.cursor.0 -= 1;
+                    self.highlight = self.cursor;
+                    return;
+                }
+            }
+        }
+        if self.config.editor.auto_indent && matches!(c, '}' | ')' | ']') {
+            self.smart_dedent();
+        }
+        self.insert_literal(c);
+    }
+
+    // Surrounds the selection with a pair of brackets or quotes, keeping it selected
+    fn wrap_selection(&mut self, open: char, close: char) {
+        let (start, end) = self.selection();
+        let x = byte_index(&self.text[end.1], end.0);
+        self.text[end.1].insert(x, close);
+        let x = byte_index(&self.text[start.1], start.0);
+        self.text[start.1].insert(x, open);
+        for pos in [&mut self.cursor, &mut self.highlight] {
+            if pos.1 == start.1 {
+                pos.0 += 1;
+            }
+        }
+    }
+
+    fn insert_literal(&mut self, c: char) {
+        if let Some(line) = self.text.get_mut(self.cursor.1) {
+            line.insert(byte_index(line, self.cursor.0), c);
+            self.cursor.0 += 1;
+            self.highlight = self.cursor;
+        }
+    }
+
+    // Inserts text without line breaks as is
+    fn insert_str(&mut self, s: &str) {
+        if let Some(line) = self.text.get_mut(self.cursor.1) {
+            line.insert_str(byte_index(line, self.cursor.0), s);
+            self.cursor.0 += line_len(s);
+            self.highlight = self.cursor;
+        }
+    }
+
+    // Space for the line numbers, based on the last line so it doesn't change while scrolling
+    fn gutter_width(&self) -> usize {
+        (self.text.len().to_string().len() + 1).max(self.config.editor.min_gutter_width)
+    }
+
+    fn tab_width(&self) -> usize {
+        self.config.editor.tab_width.max(1)
+    }
+
+    fn dedent_width(&self, indent: &str) -> usize {
+        if indent.ends_with('\t') {
+            return 1;
+        }
+        let tab_width = self.tab_width();
+        let spaces = indent.len() - indent.trim_end_matches(' ').len();
+        if self.config.editor.soft_tabs {
+            // Snap back to the previous tab stop
+            match spaces % tab_width {
+                0 => spaces.min(tab_width),
+                n => n,
+            }
+        } else {
+            spaces.min(tab_width)
+        }
+    }
+
+    fn smart_dedent(&mut self) {
+        if let Some(line) = self.text.get(self.cursor.1) {
+            if !line.trim().is_empty() {
                 return;
             }
-            let (left, right) = line.split_at(self.cursor.0 as usize);
-            let right = String::from(right);
-            self.text[self.cursor.1 as usize] = String::from(left);
-            self.text.insert(self.cursor.1 as usize + 1, right);
+            let x = self.cursor_index();
+            let remove = self.dedent_width(&line[..x]);
+            if remove > 0 {
+                self.text[self.cursor.1].replace_range(x - remove..x, "");
+                self.cursor.0 -= remove;
+                self.highlight = self.cursor;
+            }
+        }
+    }
+
+    // Removes one level of indentation from the current line, or every selected line
+    pub fn dedent_lines(&mut self) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            return;
+        }
+        let (start, end) = self.selection();
+        // A selection ending at the start of a line doesn't include that line
+        let last = if end.1 > start.1 && end.0 == 0 { end.1 - 1 } else { end.1 };
+        self.edit(EditKind::Other, |fox| {
+            for y in start.1..=last {
+                let line = &fox.text[y];
+                let indent = line.len() - line.trim_start().len();
+                let remove = fox.dedent_width(&line[..indent]);
+                if remove == 0 {
+                    continue;
+                }
+                // Only spaces and tabs are removed, so the columns change by as many bytes
+                let (indent_cols, remove_cols) = (char_col(line, indent), remove);
+                fox.text[y].replace_range(indent - remove..indent, "");
+                for pos in [&mut fox.cursor, &mut fox.highlight] {
+                    if pos.1 == y {
+                        let x = pos.0;
+                        pos.0 = if x >= indent_cols { x - remove_cols } else { x.min(indent_cols - remove_cols) };
+                    }
+                }
+            }
+        });
+    }
+
+    // Removes the indentation all selected lines share, keeping how they're indented relative to each other
+    pub fn dedent_common(&mut self) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            return;
+        }
+        let (start, end) = self.selection();
+        let last = if end.1 > start.1 && end.0 == 0 { end.1 - 1 } else { end.1 };
+        let tab_width = self.tab_width();
+        let common = (start.1..=last)
+            .map(|y| &self.text[y])
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| advance_col(leading_whitespace(line), 0, tab_width))
+            .min()
+            .unwrap_or(0);
+        if common == 0 {
+            self.status = String::from("No common indentation");
+            self.bell();
+            return;
+        }
+        self.edit(EditKind::Other, |fox| {
+            for y in start.1..=last {
+                let line = &fox.text[y];
+                // Blank lines may be indented less than the rest, so count columns instead of assuming `common` fits
+                let mut col = 0;
+                let mut remove = 0;
+                for c in leading_whitespace(line).chars() {
+                    col = if c == '\t' { col + tab_stop(col, tab_width) } else { col + 1 };
+                    if col > common {
+                        break;
+                    }
+                    remove += c.len_utf8();
+                }
+                let remove_cols = char_col(line, remove);
+                fox.text[y].replace_range(..remove, "");
+                for pos in [&mut fox.cursor, &mut fox.highlight] {
+                    if pos.1 == y {
+                        pos.0 = pos.0.saturating_sub(remove_cols);
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn pop_char(&mut self) {
+        if let Some(popup) = &mut self.popup {
+            popup.buf.pop();
+        } else if let Some(prompt) = &mut self.prompt {
+            prompt.buf.pop();
+            self.update_prompt();
+        } else if self.highlight != self.cursor {
+            self.edit(EditKind::Other, Self::delete_selection);
+        } else if self.cursor == (0, 0) {
+            // Nothing to delete
+        } else if self.in_empty_pair() {
+            self.edit(EditKind::Delete, |fox| {
+                fox.delete();
+                fox.backspace();
+            });
+        } else {
+            let continues = self.continues_edit();
+            self.edit(EditKind::Delete, Self::backspace);
+            match &mut self.last_action {
+                Some(Action::Backspace(n)) if continues => *n += 1,
+                _ => self.last_action = Some(Action::Backspace(1)),
+            }
+        }
+    }
+
+    // Deletes back to the previous word boundary, at the start of a line it joins it to the one above
+    pub fn pop_word(&mut self) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            return;
+        }
+        if self.highlight != self.cursor || self.cursor.0 == 0 {
+            self.pop_char();
+            return;
+        }
+        self.edit(EditKind::Other, |fox| {
+            let line = &fox.text[fox.cursor.1];
+            let x = fox.cursor_index();
+            let start = word_boundary(line, x, false);
+            fox.cursor.0 = char_col(line, start);
+            fox.highlight = fox.cursor;
+            fox.text[fox.cursor.1].replace_range(start..x, "");
+        });
+    }
+
+    // Whether the cursor sits between a bracket or quote and its closing pair
+    fn in_empty_pair(&self) -> bool {
+        if !self.config.editor.auto_pairs {
+            return false;
+        }
+        let line = &self.text[self.cursor.1];
+        let x = self.cursor_index();
+        let prev = line[..x].chars().next_back();
+        let next = line[x..].chars().next();
+        prev.and_then(closing_pair).is_some_and(|close| next == Some(close))
+    }
+
+    fn backspace(&mut self) {
+        let remove = if let Some(line) = self.text.get_mut(self.cursor.1) {
+            let x = byte_index(line, self.cursor.0);
+            if let Some(c) = line[..x].chars().next_back() {
+                self.cursor.0 -= 1;
+                line.remove(x - c.len_utf8());
+                self.highlight = self.cursor;
+                false
+            } else {
+                self.cursor.1 != 0
+            }
+        } else {
+            false
+        };
+
+        if remove {
+            let cur = self.text.get(self.cursor.1).unwrap().clone();
+            self.text.remove(self.cursor.1);
+            self.remove_ending(self.cursor.1 - 1);
+            self.cursor_vertical(-1);
+            self.cursor_end_of_line();
+            if let Some(line) = self.text.get_mut(self.cursor.1) {
+                line.push_str(&cur);
+            }
+        }
+    }
+
+    pub fn pop_char_del(&mut self) {
+        if self.prompt.is_none() && self.popup.is_none() {
+            let last = self.text.len() - 1;
+            if self.highlight != self.cursor {
+                self.edit(EditKind::Other, Self::delete_selection);
+            } else if self.cursor == (line_len(&self.text[last]), last) {
+                // Nothing to delete
+            } else {
+                let continues = self.continues_edit();
+                self.edit(EditKind::Delete, Self::delete);
+                match &mut self.last_action {
+                    Some(Action::Delete(n)) if continues => *n += 1,
+                    _ => self.last_action = Some(Action::Delete(1)),
+                }
+            }
+        }
+    }
+
+    fn delete(&mut self) {
+        if let Some(line) = self.text.get_mut(self.cursor.1) {
+            let x = byte_index(line, self.cursor.0);
+            if x < line.len() {
+                line.remove(x);
+            }
+        }
+    }
+
+    pub fn enter(&mut self) {
+        let continues = self.continues_edit();
+        self.edit(EditKind::Space, Self::new_line);
+        self.record_insert('\n', continues);
+    }
+
+    // Splits the line at the cursor, carrying over indentation and line comments to the new line
+    fn new_line(&mut self) {
+        let prefix = self.new_line_prefix();
+        // Splitting after a `{` indents the new line one level deeper, and a `}` right after the cursor gets its own line
+        let (opens, closes) = if self.config.editor.auto_indent && prefix.trim().is_empty() {
+            let line = &self.text[self.cursor.1];
+            let x = self.cursor_index();
+            let opens = line[..x].trim_end().ends_with('{');
+            (opens, opens && line[x..].trim_start().starts_with('}'))
+        } else {
+            (false, false)
+        };
+        self.split_line();
+        if !prefix.is_empty() || opens {
+            let indent = if opens { format!("{}{}", prefix, self.indent_unit()) } else { prefix.clone() };
+            let y = self.cursor.1;
+            let rest = self.text[y].trim_start().to_string();
+            if closes {
+                self.text[y] = indent.clone();
+                self.text.insert(y + 1, format!("{}{}", prefix, rest));
+                self.insert_ending(y + 1);
+            } else {
+                self.text[y] = format!("{}{}", indent, rest);
+            }
+            self.cursor.0 = line_len(&indent);
+            self.highlight = self.cursor;
+        }
+    }
+
+    // One level of indentation
+    fn indent_unit(&self) -> String {
+        if self.config.editor.soft_tabs {
+            " ".repeat(self.tab_width())
+        } else {
+            String::from("\t")
+        }
+    }
+
+    // What a line split off at the cursor starts with
+    fn new_line_prefix(&self) -> String {
+        if !self.config.editor.auto_indent {
+            return String::new();
+        }
+        let line = &self.text[self.cursor.1];
+        let x = self.cursor_index();
+        let indent = leading_whitespace(line);
+        // Splitting inside the indentation moves the rest of the line down as it is
+        if x < indent.len() {
+            return String::new();
+        }
+        let mut prefix = indent.to_string();
+        if let Some(token) = line_comment(&self.syntax.name).filter(|_| self.config.editor.continue_comments) {
+            let rest = &line[indent.len()..];
+            if rest.starts_with(token) && x >= indent.len() + token.len() {
+                // Keep doc comment markers like `///` and `//!` and the space after the token
+                let last = token.chars().next_back();
+                let extra = rest[token.len()..].chars().take_while(|&c| Some(c) == last || c == '!').map(char::len_utf8).sum::<usize>();
+                let mut len = token.len() + extra;
+                if rest[len..].starts_with(' ') {
+                    len += 1;
+                }
+                if indent.len() + len <= x {
+                    prefix.push_str(&rest[..len]);
+                }
+            }
+        }
+        prefix
+    }
+
+    fn split_line(&mut self) {
+        if let Some(line) = self.text.get_mut(self.cursor.1) {
+            let right = line.split_off(byte_index(line, self.cursor.0));
+            self.text.insert(self.cursor.1 + 1, right);
+            self.insert_ending(self.cursor.1 + 1);
             self.cursor_vertical(1);
             self.cursor_start_of_line();
         }
     }
 
-    pub fn get_selection(&self) -> String {
-        if self.cursor.1 == self.highlight.1 {
-            // Single line selection
-            if let Some(line) = self.text.get(self.cursor.1 as usize) {
-                let minx = self.cursor.0.min(self.highlight.0) as usize;
-                let maxx = self.cursor.0.max(self.highlight.0) as usize;
-                line[minx..maxx].to_string()
+    // Indentation for a new line opened next to the current one
+    fn line_indent(&self) -> String {
+        if self.config.editor.auto_indent {
+            leading_whitespace(&self.text[self.cursor.1]).to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    pub fn open_line_below(&mut self) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            return;
+        }
+        self.edit(EditKind::Other, |fox| {
+            let indent = fox.line_indent();
+            fox.text.insert(fox.cursor.1 + 1, indent.clone());
+            fox.insert_ending(fox.cursor.1 + 1);
+            fox.cursor_vertical(1);
+            fox.cursor.0 = line_len(&indent);
+            fox.highlight = fox.cursor;
+        });
+    }
+
+    pub fn open_line_above(&mut self) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            return;
+        }
+        self.edit(EditKind::Other, |fox| {
+            let indent = fox.line_indent();
+            fox.text.insert(fox.cursor.1, indent.clone());
+            fox.insert_ending(fox.cursor.1);
+            fox.cursor.0 = line_len(&indent);
+            fox.highlight = fox.cursor;
+        });
+    }
+
+    // Start and end of the selection in document order
+    pub fn selection(&self) -> ((usize, usize), (usize, usize)) {
+        if (self.highlight.1, self.highlight.0) <= (self.cursor.1, self.cursor.0) {
+            (self.highlight, self.cursor)
+        } else {
+            (self.cursor, self.highlight)
+        }
+    }
+
+    pub fn get_selection(&self) -> String {
+        let (start, end) = self.selection();
+        let mut result = String::new();
+        for y in start.1..=end.1 {
+            if let Some(line) = self.text.get(y) {
+                let min_x = if y == start.1 { byte_index(line, start.0) } else { 0 };
+                let max_x = if y == end.1 { byte_index(line, end.0) } else { line.len() };
+                result.push_str(&line[min_x..max_x]);
+                if y != end.1 {
+                    result.push('\n');
+                }
+            }
+        }
+        result
+    }
+
+    fn delete_selection(&mut self) {
+        let (start, end) = self.selection();
+        let end_line = &self.text[end.1];
+        let tail = end_line[byte_index(end_line, end.0)..].to_string();
+        let line = &mut self.text[start.1];
+        line.truncate(byte_index(line, start.0));
+        line.push_str(&tail);
+        self.text.drain(start.1 + 1..=end.1);
+        self.endings.drain(start.1.min(self.endings.len())..end.1.min(self.endings.len()));
+        self.cursor = start;
+        self.highlight = start;
+        self.ensure_cursor_visible();
+    }
+
+    pub fn cursor_start_of_line(&mut self) {
+        self.cursor.0 = 0;
+        self.highlight.0 = 0;
+    }
+
+    pub fn cursor_end_of_line(&mut self) {
+        if let Some(line) = self.text.get(self.cursor.1) {
+            self.cursor.0 = line_len(line);
+            self.highlight.0 = self.cursor.0;
+        }
+    }
+
+    pub fn cursor_vertical(&mut self, i: isize) {
+        self.highlight_vertical(i);
+        self.highlight = self.cursor;
+    }
+
+    // Moves the active end of the selection, the anchor stays put
+    pub fn highlight_vertical(&mut self, i: isize) {
+        let goal = match self.goal_column {
+            Some((col, pos)) if pos == self.cursor => col,
+            _ => self.cursor.0,
+        };
+        let old = self.cursor.1;
+        if i > 0 {
+            self.cursor.1 += i as usize;
+        } else if self.cursor.1 > 0 {
+            self.cursor.1 = self.cursor.1.saturating_sub(i.unsigned_abs());
+        } else if i < 0 {
+            self.bell();
+        }
+        if let Some(line) = self.text.get(self.cursor.1) {
+            self.cursor.0 = goal.min(line_len(line));
+        } else {
+            self.cursor.1 = old;
+            self.bell();
+        }
+        self.goal_column = Some((goal, self.cursor));
+        self.ensure_cursor_visible();
+    }
+
+    // Which row of its line the cursor is drawn on when wrapping at `wrap`, and the column in that row
+    fn cursor_row_col(&self, wrap: usize) -> (usize, usize) {
+        let col = advance_col(&self.text[self.cursor.1][..self.cursor_index()], 0, self.tab_width());
+        wrapped_col(col, &self.line_row_starts(self.cursor.1, wrap))
+    }
+
+    // Like highlight_vertical(), but through the rows of wrapped lines
+    pub fn highlight_row(&mut self, i: i16) {
+        let (width, _) = terminal_size();
+        let w = match self.wrap_width(width) {
+            Some(w) => w,
+            None => return self.highlight_vertical(i.into()),
+        };
+        let (row, col) = self.cursor_row_col(w);
+        let goal = match self.goal_column {
+            Some((col, pos)) if pos == self.cursor => col,
+            _ => col,
+        };
+        let y = self.cursor.1;
+        let (y, row) = if i < 0 {
+            if row > 0 {
+                (y, row - 1)
+            } else if y > 0 {
+                (y - 1, self.line_row_starts(y - 1, w).len() - 1)
+            } else {
+                return self.bell();
+            }
+        } else if row + 1 < self.line_row_starts(y, w).len() {
+            (y, row + 1)
+        } else if y + 1 < self.text.len() {
+            (y + 1, 0)
+        } else {
+            return self.bell();
+        };
+        // Only the last row has room for the cursor past its end
+        let starts = self.line_row_starts(y, w);
+        let col = match starts.get(row + 1) {
+            Some(&next) => starts[row] + goal.min(next - starts[row] - 1),
+            None => starts[row] + goal,
+        };
+        self.cursor = (char_at_col(&self.text[y], col, self.tab_width()), y);
+        self.goal_column = Some((goal, self.cursor));
+        self.ensure_cursor_visible();
+    }
+
+    pub fn cursor_row(&mut self, i: i16) {
+        self.highlight_row(i);
+        self.highlight = self.cursor;
+    }
+
+    // Like highlight_start_of_line(), but for the row the cursor is on when wrapping
+    pub fn highlight_start_of_row(&mut self) {
+        let (width, _) = terminal_size();
+        match self.wrap_width(width) {
+            Some(w) => {
+                let (row, _) = self.cursor_row_col(w);
+                let start = self.line_row_starts(self.cursor.1 , w)[row];
+                self.cursor.0 = char_at_col(&self.text[self.cursor.1], start, self.tab_width());
+            },
+            None => self.highlight_start_of_line(),
+        }
+    }
+
+    pub fn highlight_end_of_row(&mut self) {
+        let (width, _) = terminal_size();
+        let next_row = self.wrap_width(width).and_then(|w| self.line_row_starts(self.cursor.1, w).get(self.cursor_row_col(w).0 + 1).copied());
+        match next_row {
+            // Before the row's last char, past it would be the next row
+            Some(next) => self.cursor.0 = char_at_col(&self.text[self.cursor.1], next - 1, self.tab_width()),
+            None => self.highlight_end_of_line(),
+        }
+    }
+
+    pub fn cursor_start_of_row(&mut self) {
+        self.highlight_start_of_row();
+        self.highlight = self.cursor;
+    }
+
+    pub fn cursor_end_of_row(&mut self) {
+        self.highlight_end_of_row();
+        self.highlight = self.cursor;
+    }
+
+    // Moves the cursor and view by a page, `dir` being -1 or 1
+    pub fn page(&mut self, dir: i16) {
+        let (_, height) = terminal_size();
+        let amount = (self.text_rows(height) as usize).saturating_sub(self.config.editor.page_overlap).max(1);
+        let last = self.text.len() - 1;
+        let (y, scroll) = if dir > 0 {
+            ((self.cursor.1 + amount).min(last), (self.scroll + amount).min(last))
+        } else {
+            (self.cursor.1.saturating_sub(amount), self.scroll.saturating_sub(amount))
+        };
+        self.cursor_vertical(y as isize - self.cursor.1 as isize);
+        self.scroll = scroll;
+    }
+
+    // Scrolls the view by `scroll_lines` in direction `dir`, leaving the cursor where it is
+    pub fn scroll_view(&mut self, dir: i16) {
+        let lines = self.config.editor.scroll_lines;
+        let last = self.text.len() - 1;
+        self.scroll = if dir > 0 {
+            (self.scroll + lines).min(last)
+        } else {
+            self.scroll.saturating_sub(lines)
+        };
+    }
+
+    pub fn cursor_horizontal(&mut self, i: i16) {
+        if self.highlight != self.cursor {
+            let (start, end) = self.selection();
+            if i > 0 {
+                self.cursor = end;
+            } else {
+                self.cursor = start;
+            }
+        } else {
+            let old = self.cursor.0;
+            let old_y = self.cursor.1;
+            if i > 0 {
+                self.cursor.0 += i as usize;
+            } else if self.cursor.0 > 0 {
+                self.cursor.0 -= i.unsigned_abs() as usize;
+            } else {
+                // Start of the line and moving left
+                self.cursor_vertical(-1);
+                if self.cursor.1 != old_y { self.cursor_end_of_line(); }
+            }
+            if self.cursor.0 > line_len(&self.text[self.cursor.1]) {
+                self.cursor_vertical(1);
+                if self.cursor.1 != old_y { self.cursor_start_of_line(); } else { self.cursor.0 = old; }
+            }
+        }
+        self.highlight = self.cursor;
+    }
+
+    // Moves the active end of the selection, the anchor stays put
+    pub fn highlight_horizontal(&mut self, i: i16) {
+        let len = line_len(&self.text[self.cursor.1]);
+        if i > 0 {
+            if self.cursor.0 < len {
+                self.cursor.0 = (self.cursor.0 + i as usize).min(len);
+            } else if self.cursor.1 + 1 < self.text.len() {
+                // End of the line and moving right
+                self.highlight_vertical(1);
+                self.highlight_start_of_line();
             } else {
-                String::new()
+                self.bell();
             }
+        } else if self.cursor.0 > 0 {
+            self.cursor.0 = self.cursor.0.saturating_sub(i.unsigned_abs() as usize);
+        } else if self.cursor.1 > 0 {
+            // Start of the line and moving left
+            self.highlight_vertical(-1);
+            self.highlight_end_of_line();
         } else {
-            // Multi line selection
-            todo!();
+            self.bell();
         }
     }
 
-    pub fn cursor_start_of_line(&mut self) {
+    pub fn highlight_start_of_line(&mut self) {
         self.cursor.0 = 0;
-        self.highlight.0 = 0;
     }
 
-    pub fn cursor_end_of_line(&mut self) {
-        if let Some(line) = self.text.get(self.cursor.1 as usize) {
-            self.cursor.0 = line.len() as u16;
-            self.highlight.0 = self.cursor.0;
-        }
+    pub fn highlight_end_of_line(&mut self) {
+        self.cursor.0 = line_len(&self.text[self.cursor.1]);
     }
 
-    pub fn cursor_vertical(&mut self, i: i16) {
-        let old = self.cursor.1;
-        if i > 0 {
-            self.cursor.1 += i as u16;
-        } else if self.cursor.1 > 0 {
-            self.cursor.1 -= i.abs() as u16;
+    // Selects the cursor line with its line break, or the next line as well if whole lines are already selected
+    pub fn select_line(&mut self) {
+        let whole_lines = self.highlight.0 == 0 && self.cursor.0 == 0 && self.cursor.1 > self.highlight.1;
+        if !whole_lines {
+            self.highlight = (0, self.cursor.1);
         }
-        if let Some(line) = self.text.get(self.cursor.1 as usize) {
-            if self.cursor.0 > line.len() as u16 {
-                self.cursor.0 = line.len() as u16;
+        let y = self.cursor.1;
+        // The last line has no line break to take
+        self.cursor = if y + 1 < self.text.len() {
+            (0, y + 1)
+        } else {
+            (line_len(&self.text[y]), y)
+        };
+        self.goal_column = None;
+        self.ensure_cursor_visible();
+    }
+
+    pub fn highlight_word(&mut self, i: i16) {
+        let line = &self.text[self.cursor.1];
+        let x = self.cursor_index();
+        if i > 0 && x >= line.len() {
+            if self.cursor.1 + 1 < self.text.len() {
+                self.highlight_vertical(1);
+                self.highlight_start_of_line();
+            }
+        } else if i < 0 && x == 0 {
+            if self.cursor.1 > 0 {
+                self.highlight_vertical(-1);
+                self.highlight_end_of_line();
             }
         } else {
-            self.cursor.1 = old;
+            self.cursor.0 = char_col(line, word_boundary(line, x, i > 0));
         }
+    }
+
+    pub fn cursor_word(&mut self, i: i16) {
+        self.highlight_word(i);
+        self.highlight = self.cursor;
+    }
+
+    pub fn go_to_line(&mut self, line: usize) {
+        self.cursor = (0, line);
         self.highlight = self.cursor;
+        self.clamp_cursor();
+    }
+
+    pub fn cursor_start_of_file(&mut self) {
+        self.go_to_line(0);
+    }
+
+    pub fn cursor_end_of_file(&mut self) {
+        self.go_to_line(self.text.len() - 1);
+        self.cursor_end_of_line();
+    }
+
+    // Moves the active end of the selection, the anchor stays put
+    pub fn highlight_start_of_file(&mut self) {
+        self.cursor = (0, 0);
+        self.ensure_cursor_visible();
+    }
 
-        // Scrolling
-        let (_, height) = size().expect("Failed to query terminal size!");
-        let offset = self.cursor.1 as i16 - self.scroll as i16;
-        if offset >= height as i16 - 2 {
-            self.scroll += 1;
-        } else if offset < 0 {
-            self.scroll -= 1;
+    pub fn highlight_end_of_file(&mut self) {
+        self.cursor.1 = self.text.len() - 1;
+        self.highlight_end_of_line();
+        self.ensure_cursor_visible();
+    }
+
+    // Saves unsaved changes once the user has been idle for long enough, returns whether it did
+    pub fn autosave(&mut self, idle: Duration) -> bool {
+        let secs = match self.config.editor.autosave_secs {
+            Some(secs) => secs,
+            None => return false,
+        };
+        if !self.dirty || self.hex.is_some() || self.path_expanded.is_empty() || idle < Duration::from_secs(secs) {
+            return false;
+        }
+        match self.save() {
+            Ok(()) => self.status = String::from("Autosaved"),
+            Err(e) => self.status = format!("Autosave failed: {}", e),
         }
+        true
     }
 
-    //TODO: Perhaps move the cursor to the next/previous line if at the end/start of the current line?
-    pub fn cursor_horizontal(&mut self, i: i16) {
-        if self.highlight != self.cursor {
-            let (start, end) = {
-                if self.highlight.1 == self.cursor.1 {
-                    // Single line selection
-                    let min_x = self.highlight.0.min(self.cursor.0);
-                    let max_x = self.highlight.0.max(self.cursor.0);
-                    ((min_x,self.cursor.1),(max_x,self.cursor.1))
-                } else {
-                    // Multi line selection
-                    todo!();
-                }
-            };
-            if i > 0 {
-                self.cursor = end;
-            } else {
-                self.cursor = start;
+    // Checks whether the file changed on disk, reloading it or asking what to do if there are unsaved changes.
+    // Returns whether anything happened
+    pub fn check_disk(&mut self) -> bool {
+        let modified = modified_time(Path::new(&self.path_expanded));
+        if modified.is_none() || modified == self.modified || self.popup.is_some() {
+            return false;
+        }
+        self.modified = modified;
+        if !self.dirty {
+            self.reload("File changed on disk, reloaded");
+            return true;
+        }
+        let disk = match std::fs::read_to_string(&self.path_expanded) {
+            Ok(disk) => disk,
+            Err(_) => return false,
+        };
+        let description = format!("- your changes, + on disk\n{}", line_diff(&self.text.join("\n"), &disk));
+        self.popup_with(PromptType::FileChanged, description);
+        true
+    }
+
+    // Shows what saving would change in the file on disk
+    pub fn show_diff(&mut self) {
+        let disk = match std::fs::read_to_string(&self.path_expanded) {
+            Ok(disk) => disk,
+            Err(_) if self.new_file => String::new(),
+            Err(e) => {
+                self.status = format!("Could not read {}: {}", self.path, e);
+                return;
+            },
+        };
+        let diff = line_diff(&disk, &self.text.join("\n"));
+        if diff.is_empty() {
+            self.status = String::from("No changes from the file on disk");
+            return;
+        }
+        self.popup_with(PromptType::Diff, format!("- on disk, + your changes\n{}", diff));
+    }
+
+    // Loads the file from disk again, keeping the cursor where it was as far as possible
+    pub fn reload(&mut self, status: &str) {
+        let file = match load_file_in_terminal(&self.path, &self.config) {
+            Ok(file) => file,
+            Err(e) => {
+                self.status = format!("Could not reload: {}", e);
+                return;
+            },
+        };
+        self.push_undo(self.snapshot());
+        self.redo.clear();
+        self.last_edit = None;
+        self.text = file.text;
+        self.endings = file.endings;
+        self.line_ending = file.line_ending;
+        self.hex = file.bytes;
+        self.modified = file.modified;
+        self.read_only = file.read_only;
+        self.new_file = file.new_file;
+        self.syntax = file.syntax;
+        let mut config = config();
+        config.apply_filetype(&self.syntax.name);
+        self.config = config;
+        self.dirty = false;
+        self.remove_swap();
+        self.highlight = self.cursor;
+        self.goal_column = None;
+        self.clamp_cursor();
+        self.hex_cursor = self.hex_cursor.min(self.hex.as_ref().map_or(0, |b| b.len().saturating_sub(1)));
+        self.text_changed();
+        self.status = status.to_string();
+    }
+
+    // Replaces the open file with another one, remembering where we were in the old one
+    fn open_file(&mut self, filename: &str) -> Result<()> {
+        let file = load_file_in_terminal(filename, &self.config)?;
+        let mut config = config();
+        config.apply_filetype(&file.syntax.name);
+        self.config = config;
+        self.remember_position();
+        self.remove_swap();
+        self.path_expanded = file.path_expanded;
+        self.path = filename.to_string();
+        self.text = file.text;
+        self.endings = file.endings;
+        self.line_ending = file.line_ending;
+        self.cursor = file.cursor;
+        self.highlight = file.cursor;
+        self.goal_column = None;
+        self.scroll = file.scroll;
+        self.hex = file.bytes;
+        self.hex_cursor = 0;
+        self.modified = file.modified;
+        self.read_only = file.read_only;
+        self.new_file = file.new_file;
+        self.syntax = file.syntax;
+        self.status = file.status;
+        self.dirty = false;
+        self.undo.clear();
+        self.redo.clear();
+        self.last_edit = None;
+        self.start_lsp();
+        self.touch_recent();
+        Ok(())
+    }
+
+    // Opens `filename` from the Open prompt at its top, unsaved changes are asked about before this
+    pub fn open(&mut self, filename: &str) {
+        if position_key(Path::new(&expand_path(filename))) == position_key(Path::new(&self.path_expanded)) && !self.dirty {
+            return;
+        }
+        match self.open_file(filename) {
+            Ok(()) => {
+                self.cursor = (0, 0);
+                self.highlight = self.cursor;
+                self.scroll = 0;
+            },
+            Err(e) => self.status = format!("Could not open {}: {}", filename, e),
+        }
+    }
+
+    // Opens the `n`th file of the recent files list, counting from 1
+    pub fn open_recent(&mut self, n: usize) {
+        let path = match n.checked_sub(1).and_then(|i| state::recent_files().into_iter().nth(i)) {
+            Some(path) => path,
+            None => {
+                self.status = String::from("No such recent file");
+                self.bell();
+                return;
+            },
+        };
+        if position_key(Path::new(&path)) == position_key(Path::new(&self.path_expanded)) {
+            return;
+        }
+        if self.dirty {
+            self.status = String::from("Unsaved changes, save before opening another file");
+            return;
+        }
+        if let Err(e) = self.open_file(&path) {
+            self.status = format!("Could not open {}: {}", path, e);
+        }
+    }
+
+    // Opens the file paired with this one, like a C file's header
+    pub fn open_pair(&mut self) {
+        // The longest matching ending wins, so mod.rs isn't taken for any .rs file
+        let pair = self.config.pairs.iter()
+            .filter(|(from, _)| self.path_expanded.ends_with(from.as_str()))
+            .max_by_key(|(from, _)| from.len())
+            .and_then(|(from, to)| {
+                let stem = &self.path_expanded[..self.path_expanded.len() - from.len()];
+                to.iter().map(|to| format!("{}{}", stem, to)).find(|path| Path::new(path).is_file())
+            });
+        let path = match pair {
+            Some(path) => path,
+            None => {
+                self.status = String::from("No paired file found");
+                self.bell();
+                return;
+            },
+        };
+        if self.dirty {
+            self.status = String::from("Unsaved changes, save before opening another file");
+            return;
+        }
+        if let Err(e) = self.open_file(&path) {
+            self.status = format!("Could not open {}: {}", path, e);
+        }
+    }
+
+    pub fn jump_to_tag(&mut self) {
+        let name = word_at(&self.text[self.cursor.1], self.cursor_index()).to_string();
+        if name.is_empty() {
+            self.status = String::from("No tag under cursor");
+            return;
+        }
+        let (file, address) = match find_tag(Path::new(&self.path_expanded), &name) {
+            Some(tag) => tag,
+            None => {
+                self.status = format!("Tag not found: {}", name);
+                return;
+            },
+        };
+        let jump = Jump {
+            path: self.path.clone(),
+            cursor: self.cursor,
+            scroll: self.scroll,
+        };
+        if position_key(&file) != position_key(Path::new(&self.path_expanded)) {
+            if self.dirty {
+                self.status = String::from("Unsaved changes, save before jumping to another file");
+                return;
             }
-        } else {
-            let old = self.cursor.0;
-            let old_y = self.cursor.1;
-            if i > 0 {
-                self.cursor.0 += i as u16;
-            } else if self.cursor.0 > 0 {
-                self.cursor.0 -= i.abs() as u16;
-            } else {
-                // Start of the line and moving left
-                self.cursor_vertical(-1);
-                if self.cursor.1 != old_y { self.cursor_end_of_line(); }
+            if let Err(e) = self.open_file(&file.display().to_string()) {
+                self.status = format!("Could not open {}: {}", file.display(), e);
+                return;
             }
-            if self.cursor.0 as usize > self.text[self.cursor.1 as usize].len() {
-                self.cursor_vertical(1);
-                if self.cursor.1 != old_y { self.cursor_start_of_line(); } else { self.cursor.0 = old; }
+        }
+        self.jumps.push(jump);
+        match tag_line(&self.text, &address) {
+            Some(line) => self.go_to_line(line),
+            None => self.status = format!("Tag {} moved, it is no longer in this file", name),
+        }
+    }
+
+    pub fn jump_back(&mut self) {
+        let jump = match self.jumps.last() {
+            Some(jump) => jump,
+            None => {
+                self.status = String::from("No jump to go back to");
+                return;
+            },
+        };
+        if jump.path != self.path {
+            if self.dirty {
+                self.status = String::from("Unsaved changes, save before jumping to another file");
+                return;
+            }
+            let path = jump.path.clone();
+            if let Err(e) = self.open_file(&path) {
+                self.status = format!("Could not open {}: {}", path, e);
+                return;
             }
         }
+        let jump = self.jumps.pop().unwrap();
+        self.cursor = jump.cursor;
         self.highlight = self.cursor;
+        self.scroll = jump.scroll;
+        self.clamp_cursor();
     }
 
-    pub fn highlight_horizontal(&mut self, i: i16) {
-        let old = self.highlight.0;
-        if i > 0 {
-            self.highlight.0 += i as u16;
-        } else if self.highlight.0 > 0 {
-            self.highlight.0 -= i.abs() as u16;
+    pub fn paste(&mut self, text: &str) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            for c in text.lines().flat_map(str::chars) {
+                self.push_char(c);
+            }
+            return;
+        }
+        // As is, typing it would have auto-pairs and smart dedent rewrite it
+        self.edit(EditKind::Other, |fox| {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    fox.split_line();
+                }
+                fox.insert_str(line.strip_suffix('\r').unwrap_or(line));
+            }
+            fox.clamp_cursor();
+        });
+    }
+
+    // Does what a shortcut key is bound to, returns whether to quit
+    fn run_command(&mut self, command: Command) -> bool {
+        match command {
+            Command::Help => self.popup(PromptType::Help),
+            Command::Save => {
+                if let Err(e) = self.save() {
+                    self.status = format!("Could not save: {}", e);
+                }
+            },
+            Command::SaveAs => self.prompt(PromptType::SaveAs),
+            Command::Open => self.prompt(PromptType::Open),
+            Command::Quit if self.dirty => self.prompt(PromptType::UnsavedQuit),
+            Command::Quit => return true,
+            Command::Find => self.prompt(PromptType::Find),
+            Command::FindPrevious => self.find_previous(),
+            Command::ToggleCase => self.toggle_ignore_case(),
+            Command::Replace => self.prompt(PromptType::Replace),
+            Command::ReplaceAll => self.prompt(PromptType::ReplaceAll),
+            Command::GoToLine => self.prompt(PromptType::GoToLine),
+            Command::SelectLine => self.select_line(),
+            Command::DedentCommon => self.dedent_common(),
+            Command::Copy => self.copy(),
+            Command::Cut => self.cut(),
+            Command::Paste => {
+                let clipboard = self.clipboard();
+                self.paste(&clipboard);
+            },
+            Command::PasteBlock => {
+                let clipboard = self.clipboard();
+                self.paste_block(&clipboard);
+            },
+            Command::PasteRegister => {
+                let register = self.register.clone();
+                self.paste(&register);
+            },
+            Command::Undo => self.undo(),
+            Command::Redo => self.redo(),
+            Command::Repeat => self.repeat_edit(),
+            Command::OpenLineBelow => self.open_line_below(),
+            Command::OpenLineAbove => self.open_line_above(),
+            Command::SwapUp => self.swap_up(),
+            Command::SwapDown => self.swap_down(),
+            Command::WordLeft => self.cursor_word(-1),
+            Command::WordRight => self.cursor_word(1),
+            Command::SelectWordLeft => self.highlight_word(-1),
+            Command::SelectWordRight => self.highlight_word(1),
+            Command::DeleteWord => self.pop_word(),
+            Command::Dedent => self.dedent_lines(),
+            Command::StartOfFile => self.cursor_start_of_file(),
+            Command::EndOfFile => self.cursor_end_of_file(),
+            Command::SelectToStartOfFile => self.highlight_start_of_file(),
+            Command::SelectToEndOfFile => self.highlight_end_of_file(),
+            Command::Verbatim => {
+                self.verbatim = true;
+                self.status = String::from("Insert literally: press a key");
+            },
+            Command::RecentFiles => self.popup(PromptType::RecentFiles),
+            Command::Info => {
+                let info = self.info();
+                self.popup_with(PromptType::Info, info);
+            },
+            Command::Diff => self.show_diff(),
+            Command::OpenPair => self.open_pair(),
+            Command::Reload if self.dirty => self.prompt(PromptType::Reload),
+            Command::Reload => self.reload("Reloaded from disk"),
+            Command::LineEnding => self.prompt(PromptType::LineEnding),
+            Command::JumpToTag => self.jump_to_tag(),
+            Command::JumpBack => self.jump_back(),
+        }
+        false
+    }
+
+    pub fn tab(&mut self) {
+        let takes_tab = match (&self.popup, &self.prompt) {
+            (Some(_), _) => false,
+            (None, Some(prompt)) => prompt.prompt.takes_tab(),
+            (None, None) => true,
+        };
+        if !takes_tab {
+            return;
+        }
+        if self.config.editor.soft_tabs && self.prompt.is_none() {
+            // Spaces up to the next tab stop
+            let col = advance_col(&self.text[self.cursor.1][..self.cursor_index()], 0, self.tab_width());
+            for _ in 0..tab_stop(col, self.tab_width()) {
+                self.push_char(' ');
+            }
+        } else {
+            self.push_char('\t');
+        }
+    }
+
+    // Copies the selection, or the whole line when nothing is selected, to the system clipboard
+    // and to our own register, which works even without a clipboard
+    pub fn copy(&mut self) {
+        self.register = if self.highlight != self.cursor {
+            self.get_selection()
+        } else {
+            format!("{}\n", self.text[self.cursor.1])
+        };
+        self.status = match terminal_clipboard::set_string(&self.register) {
+            Ok(_) => String::from("Copied!"),
+            Err(_) => String::from("Kept for alt-p, the system clipboard couldn't be reached"),
+        };
+    }
+
+    // Copies and then deletes the selection, or the whole line when nothing is selected
+    pub fn cut(&mut self) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            return;
         }
-        if self.highlight.0 as usize > self.text[self.highlight.1 as usize].len() {
-            self.highlight.0 = old;
+        let whole_line = self.highlight == self.cursor;
+        self.copy();
+        self.edit(EditKind::Other, |fox| {
+            if !whole_line {
+                fox.delete_selection();
+            } else if fox.text.len() > 1 {
+                let y = fox.cursor.1;
+                fox.text.remove(y);
+                fox.remove_ending(y);
+                fox.cursor = (0, y.min(fox.text.len() - 1));
+                fox.highlight = fox.cursor;
+            } else {
+                fox.text[0].clear();
+                fox.endings.clear();
+                fox.cursor = (0, 0);
+                fox.highlight = fox.cursor;
+            }
+        });
+        if self.status == "Copied!" {
+            self.status = String::from("Cut!");
         }
     }
 
-    pub fn go_to_line(&mut self, line: u16) {
-        let i = line.min(self.text.len() as u16 - 1);
-        self.cursor.1 = i;
-        self.highlight.1 = self.cursor.1;
-        self.cursor_start_of_line();
-        // Scrolling
-        let (_, height) = size().expect("Failed to query terminal size!");
-        let offset = self.cursor.1 as i16 - self.scroll as i16;
-        if offset >= height as i16 - 2 {
-            self.scroll += offset as u16;
-        } else if offset < 0 {
-            self.scroll -= offset.abs() as u16;
+    // What to paste, from the system clipboard if it can be read and our register otherwise
+    fn clipboard(&self) -> String {
+        terminal_clipboard::get_string().unwrap_or_else(|_| self.register.clone())
+    }
+
+    // Pastes with every line after the first starting at the cursor's column, so indented snippets keep their shape
+    pub fn paste_block(&mut self, text: &str) {
+        if self.prompt.is_some() || self.popup.is_some() {
+            self.paste(text);
+            return;
         }
+        let before = &self.text[self.cursor.1][..self.cursor_index()];
+        let prefix = if before.trim().is_empty() {
+            before.to_string()
+        } else {
+            " ".repeat(advance_col(before, 0, self.tab_width()))
+        };
+        self.edit(EditKind::Other, |fox| {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    fox.split_line();
+                    if !line.is_empty() {
+                        fox.insert_str(&prefix);
+                    }
+                }
+                fox.insert_str(line.strip_suffix('\r').unwrap_or(line));
+            }
+            fox.clamp_cursor();
+        });
     }
 
     pub fn swap_down(&mut self) {
-        if let Some(line_down) = self.text.get(self.cursor.1 as usize + 1) {
-            let line = self.text.get(self.cursor.1 as usize).expect("How did we get here?").clone();
-            self.text[self.cursor.1 as usize] = line_down.clone();
-            self.text[self.cursor.1 as usize + 1] = line.to_string();
-            self.cursor_vertical(1);
-            self.dirty = true;
+        let y = self.cursor.1;
+        if y + 1 < self.text.len() {
+            self.edit(EditKind::Other, |fox| {
+                fox.text.swap(y, y + 1);
+                if y + 1 < fox.endings.len() {
+                    fox.endings.swap(y, y + 1);
+                }
+                fox.cursor_vertical(1);
+            });
         }
     }
 
     pub fn swap_up(&mut self) {
-        self.cursor_vertical(-1);
-        self.swap_down();
-        self.cursor_vertical(-1);
+        let y = self.cursor.1;
+        if y > 0 {
+            self.edit(EditKind::Other, |fox| {
+                fox.text.swap(y - 1, y);
+                if y < fox.endings.len() {
+                    fox.endings.swap(y - 1, y);
+                }
+                fox.cursor_vertical(-1);
+            });
+        }
     }
 }
 
 impl Drop for Fox {
     fn drop(&mut self) {
-        let _ = stdout().execute(LeaveAlternateScreen);
+        if !self.terminal {
+            return;
+        }
+        if self.alt_screen {
+            let _ = stdout().execute(LeaveAlternateScreen);
+        } else if let Ok((_, height)) = size() {
+            // Leave what we drew on screen and continue below it
+            let _ = stdout().execute(cursor::MoveTo(0, height - 1));
+            println!();
+        }
+        let _ = stdout().execute(PopKeyboardEnhancementFlags);
+        let _ = stdout().execute(DisableMouseCapture);
         let _ = disable_raw_mode();
         let _ = stdout().execute(cursor::Show);
     }
 }
 
-pub fn run(filename: &str) -> Result<()> {
-    let mut editor = Fox::new(filename)?;
-    'app: loop {
-        match read()? {
-            Event::Key(key) => {
-                if key.modifiers.contains(KeyModifiers::CONTROL) {
-                    match key.code {
-                        KeyCode::Char('q') => {
-                            if editor.dirty {
-                                editor.prompt(PromptType::UnsavedQuit);
-                            } else {
-                                break 'app;
-                            }
-                        },
-                        KeyCode::Char('s') => editor.save()?, //TODO: If also holding shift, save as?
-                        KeyCode::Char('f') => editor.prompt(PromptType::Find),
-                        KeyCode::Char('h') => editor.popup(PromptType::Help),
-                        KeyCode::Char('k') => editor.prompt(PromptType::GoToLine),
-                        KeyCode::Char('v') => {
-                            if let Ok(clipboard) = terminal_clipboard::get_string() {
-                                for line in clipboard.lines() {
-                                    for c in line.chars() {
-                                        editor.push_char(c);
-                                    }
-                                    editor.enter();
-                                    editor.cursor_start_of_line();
-                                }
-                            }
-                        }
-                        KeyCode::Char('c') => { let _ = terminal_clipboard::set_string(editor.get_selection()); },
-
-                        KeyCode::Down => editor.swap_down(),
-                        KeyCode::Up => editor.swap_up(),
+// Prints the file highlighted to stdout, without colors if NO_COLOR is set
+pub fn cat(filename: &str) -> Result<()> {
+    let config = config();
+    // load_file treats missing files as new ones, here they're an error
+    std::fs::metadata(expand_path(filename))
+        .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", filename, e)))?;
+    let file = load_file(filename, &config)?;
+    if file.bytes.is_some() {
+        return Err(std::io::Error::other(format!("{} is a binary file", filename)));
+    }
+    let color = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    let mut h = HighlightLines::new(&file.syntax, config_theme(&config));
+    let mut out = stdout().lock();
+    for line in &file.text {
+        if color {
+            let ranges: Vec<(Style, &str)> = h.highlight(line, &carbon_dump::SYNTAX_SET);
+            writeln!(out, "{}\x1b[0m", as_24_bit_terminal_escaped(&ranges[..], false))?;
+        } else {
+            writeln!(out, "{}", line)?;
+        }
+    }
+    Ok(())
+}
 
-                        _ => {},
-                    }
-                } else if key.modifiers.contains(KeyModifiers::SHIFT) {
-                    match key.code {
-                        KeyCode::Char(c) => c.to_uppercase().for_each(|c| editor.push_char(c)),
-                        KeyCode::Left => editor.highlight_horizontal(-1),
-                        KeyCode::Right => editor.highlight_horizontal(1),
-                        _ => {},
+pub fn run(filename: &str, alt_screen: bool) -> Result<()> {
+    let mut editor = Fox::new(filename, alt_screen)?;
+    editor.redraw()?;
+    let mut last_input = Instant::now();
+    let mut idle_ticks = 0;
+    let mut autosave_pending = true; // Only try autosaving once per idle period, so failures aren't retried endlessly
+    'app: loop {
+        // Wake up now and then to hear from the language server, notice changes on disk and autosave
+        if !poll(Duration::from_millis(100))? {
+            let autosaved = autosave_pending && editor.autosave(last_input.elapsed());
+            if autosaved {
+                autosave_pending = false;
+            }
+            if idle_ticks == SWAP_DELAY_TICKS {
+                editor.write_swap();
+            }
+            idle_ticks += 1;
+            if editor.poll_lsp() | editor.check_disk() | autosaved {
+                editor.redraw()?;
+            }
+            continue;
+        }
+        let event = read()?;
+        if let Event::Resize(_, _) = event {
+            // Rows may have gone, keep the cursor and the popup's text in view
+            editor.ensure_cursor_visible();
+            editor.scroll_popup(0);
+            editor.redraw()?;
+            continue;
+        }
+        if let Event::Mouse(mouse) = event {
+            match mouse.kind {
+                MouseEventKind::ScrollUp if editor.popup.is_some() => editor.scroll_popup(-(editor.config.editor.scroll_lines as isize)),
+                MouseEventKind::ScrollDown if editor.popup.is_some() => editor.scroll_popup(editor.config.editor.scroll_lines as isize),
+                MouseEventKind::ScrollUp => editor.scroll_view(-1),
+                MouseEventKind::ScrollDown => editor.scroll_view(1),
+                MouseEventKind::Down(MouseButton::Left) | MouseEventKind::Drag(MouseButton::Left) => {
+                    if !editor.scrollbar_click(mouse.column, mouse.row) {
+                        continue;
                     }
-                } else {
-                    match key.code {
-                        KeyCode::Char(c) => editor.push_char(c),
-                        KeyCode::Tab => editor.push_char('\t'),
-                        KeyCode::Backspace => editor.pop_char(),
-                        KeyCode::Delete => editor.pop_char_del(),
-                        KeyCode::Enter => {
-                            fn handle_prompt(editor: &mut Fox, prompt: Prompt, is_popup: bool) -> bool {
-                                let ans = &prompt.buf;
-                                if match prompt.prompt {
-                                    PromptType::UnsavedQuit => { if ans == "y" || ans == "ye" || ans == "yes" { return true; }; true },
-                                    PromptType::Find => {
+                },
+                // Don't redraw for every mouse movement
+                _ => continue,
+            }
+        }
+        last_input = Instant::now();
+        autosave_pending = true;
+        idle_ticks = 0;
+        if let Event::Key(key) = event {
+            // Some terminals also report key releases, only handle presses and repeats
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            if editor.hex.is_some() && editor.popup.is_none() {
+                match (command_for(&key), key.code) {
+                    (Some(Command::Quit), _) => break 'app,
+                    (Some(command @ (Command::Help | Command::JumpBack | Command::Info | Command::RecentFiles)), _) => { editor.run_command(command); },
+                    (_, KeyCode::Left) => editor.hex_move(-1),
+                    (_, KeyCode::Right) => editor.hex_move(1),
+                    (_, KeyCode::Up) => editor.hex_move(-(HEX_ROW as isize)),
+                    (_, KeyCode::Down) => editor.hex_move(HEX_ROW as isize),
+                    (_, KeyCode::Home) => editor.hex_move(-((editor.hex_cursor % HEX_ROW) as isize)),
+                    (_, KeyCode::End) => editor.hex_move((HEX_ROW - 1 - editor.hex_cursor % HEX_ROW) as isize),
+                    _ => editor.status = String::from("Hex view is read-only"),
+                }
+                editor.redraw()?;
+                continue;
+            }
+            if editor.verbatim {
+                editor.verbatim = false;
+                match key.code {
+                    KeyCode::Char(c) => editor.push_char_literal(c),
+                    KeyCode::Tab => editor.push_char_literal('\t'),
+                    _ => {},
+                }
+                editor.redraw()?;
+                continue;
+            }
+            if key.modifiers.contains(KeyModifiers::CONTROL | KeyModifiers::ALT) && matches!(key.code, KeyCode::Char(_)) {
+                // AltGr is reported as ctrl+alt on some platforms
+                if let KeyCode::Char(c) = key.code {
+                    editor.push_char(c);
+                }
+            } else if let Some(command) = command_for(&key) {
+                if editor.run_command(command) {
+                    break 'app;
+                }
+            } else if key.modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) {
+                // Unbound shortcut
+            } else if key.modifiers.contains(KeyModifiers::SHIFT) {
+                match key.code {
+                    KeyCode::Char(c) => c.to_uppercase().for_each(|c| editor.push_char(c)),
+                    KeyCode::Left => editor.highlight_horizontal(-1),
+                    KeyCode::Right => editor.highlight_horizontal(1),
+                    KeyCode::Up => editor.highlight_row(-1),
+                    KeyCode::Down => editor.highlight_row(1),
+                    KeyCode::Home => editor.highlight_start_of_row(),
+                    KeyCode::End => editor.highlight_end_of_row(),
+                    _ => {},
+                }
+            } else {
+                match key.code {
+                    KeyCode::Char(c) => editor.push_char(c),
+                    KeyCode::Tab => editor.tab(),
+                    KeyCode::Backspace => editor.pop_char(),
+                    KeyCode::Delete => editor.pop_char_del(),
+                    KeyCode::Enter => {
+                        fn handle_prompt(editor: &mut Fox, prompt: Prompt, is_popup: bool) -> bool {
+                            let ans = &prompt.buf;
+                            if match prompt.prompt {
+                                PromptType::UnsavedQuit => { if ans == "y" || ans == "ye" || ans == "yes" { return true; }; true },
+                                // Stays open so Enter goes on to the next match, Esc closes it
+                                PromptType::Find => {
+                                    if ans.is_empty() {
+                                        true
+                                    } else {
                                         let found = editor.find_next(ans);
+                                        let message = if found { editor.match_position(ans) } else { String::from("Could not find string!") };
+                                        if let Some(prompt) = &mut editor.prompt {
+                                            prompt.description = message;
+                                        }
                                         if !found {
-                                            editor.status = String::from("Could not find string!");
+                                            editor.bell();
                                         }
-                                        !found
-                                    },
-                                    PromptType::Help => true,
-                                    PromptType::GoToLine => {
-                                        if let Ok(num) = ans.parse::<u16>() {
-                                            editor.go_to_line(num.max(1) - 1);
+                                        false
+                                    }
+                                },
+                                PromptType::Replace | PromptType::ReplaceAll => {
+                                    if ans.is_empty() {
+                                        true
+                                    } else {
+                                        editor.replace_term = ans.clone();
+                                        editor.replacing_all = matches!(prompt.prompt, PromptType::ReplaceAll);
+                                        editor.prompt(PromptType::ReplaceWith);
+                                        false
+                                    }
+                                },
+                                // Stays open so Enter replaces the next match, Esc stops
+                                PromptType::ReplaceWith if !editor.replacing_all => {
+                                    let term = editor.replace_term.clone();
+                                    let replaced = editor.replace_next(&term, ans);
+                                    if !replaced {
+                                        editor.bell();
+                                    }
+                                    let left = editor.count_matches(&term);
+                                    if let Some(prompt) = &mut editor.prompt {
+                                        prompt.description = match (replaced, left) {
+                                            (false, _) => String::from("Not found"),
+                                            (true, 0) => String::from("Replaced, no matches left"),
+                                            (true, 1) => String::from("Replaced, 1 match left"),
+                                            (true, n) => format!("Replaced, {} matches left", n),
+                                        };
+                                    }
+                                    false
+                                },
+                                PromptType::ReplaceWith => {
+                                    let term = std::mem::take(&mut editor.replace_term);
+                                    editor.status = match editor.replace_all(&term, ans) {
+                                        0 => {
+                                            editor.bell();
+                                            String::from("Could not find string!")
+                                        },
+                                        1 => String::from("Replaced 1 occurrence"),
+                                        n => format!("Replaced {} occurrences", n),
+                                    };
+                                    true
+                                },
+                                PromptType::Reload => {
+                                    if ans == "y" || ans == "ye" || ans == "yes" {
+                                        editor.reload("Reloaded from disk");
+                                    }
+                                    true
+                                },
+                                PromptType::SaveAs => {
+                                    let filename = ans.trim();
+                                    let path = expand_path(filename);
+                                    if filename.is_empty() {
+                                        true
+                                    } else if Path::new(&path).exists() && position_key(Path::new(&path)) != position_key(Path::new(&editor.path_expanded)) {
+                                        editor.pending_path = filename.to_string();
+                                        editor.prompt(PromptType::Overwrite);
+                                        if let Some(prompt) = &mut editor.prompt {
+                                            prompt.description = path;
                                         }
+                                        false
+                                    } else {
+                                        editor.save_as(filename);
                                         true
                                     }
-                                } {
-                                    if is_popup {
-                                        editor.popup = None;
+                                },
+                                PromptType::Overwrite => {
+                                    let filename = std::mem::take(&mut editor.pending_path);
+                                    if ans == "y" || ans == "ye" || ans == "yes" {
+                                        editor.save_as(&filename);
+                                    }
+                                    true
+                                },
+                                PromptType::Open => {
+                                    let filename = ans.trim();
+                                    if filename.is_empty() {
+                                        true
+                                    } else if editor.dirty {
+                                        editor.pending_path = filename.to_string();
+                                        editor.prompt(PromptType::UnsavedOpen);
+                                        false
                                     } else {
-                                        editor.prompt = None;
+                                        editor.open(filename);
+                                        true
+                                    }
+                                },
+                                PromptType::UnsavedOpen => {
+                                    let filename = std::mem::take(&mut editor.pending_path);
+                                    match ans.as_str() {
+                                        "y" | "ye" | "yes" => match editor.save() {
+                                            Ok(()) => editor.open(&filename),
+                                            Err(e) => editor.status = format!("Could not save: {}", e),
+                                        },
+                                        "n" | "no" => editor.open(&filename),
+                                        _ => editor.status = String::from("Type y to save, n to discard the changes"),
+                                    }
+                                    true
+                                },
+                                PromptType::LineEnding => {
+                                    editor.convert_line_endings(ans);
+                                    true
+                                },
+                                PromptType::Help | PromptType::Info | PromptType::Diff => true,
+                                PromptType::RecentFiles => {
+                                    match ans.trim().parse::<usize>() {
+                                        Ok(n) => editor.open_recent(n),
+                                        Err(_) => editor.status = String::from("Type the number of the file to open"),
+                                    }
+                                    true
+                                },
+                                PromptType::RecoverSwap => {
+                                    if ans == "y" || ans == "ye" || ans == "yes" {
+                                        editor.recover_swap();
+                                    }
+                                    true
+                                },
+                                PromptType::FileChanged => {
+                                    if ans == "y" || ans == "ye" || ans == "yes" {
+                                        editor.reload("File changed on disk, reloaded");
+                                    }
+                                    true
+                                },
+                                PromptType::GoToLine => {
+                                    match ans.trim().parse::<usize>() {
+                                        Ok(num) => {
+                                            if num > editor.text.len() {
+                                                editor.status = format!("Only {} lines, went to the last one", editor.text.len());
+                                            }
+                                            editor.go_to_line(num.max(1) - 1);
+                                            true
+                                        },
+                                        Err(_) if ans.trim().is_empty() => true,
+                                        Err(_) => {
+                                            // Stay open so the number can be fixed
+                                            if let Some(prompt) = &mut editor.prompt {
+                                                prompt.description = String::from("Not a number");
+                                            }
+                                            editor.bell();
+                                            false
+                                        },
                                     }
                                 }
-                                false
-                            }
-                            if editor.popup.is_some() {
-                                let popup = editor.popup.as_ref().unwrap().clone();
-                                if handle_prompt(&mut editor, popup, true) { break 'app; }
-                            } else if editor.prompt.is_some() {
-                                let prompt = editor.prompt.as_ref().unwrap().clone();
-                                if handle_prompt(&mut editor, prompt, false) { break 'app; }
-                            } else {
-                                editor.enter();
-                                editor.dirty = true;
+                            } {
+                                if is_popup {
+                                    editor.popup = None;
+                                } else {
+                                    editor.prompt = None;
+                                }
                             }
-                        },
-                        KeyCode::Esc => if editor.popup.is_some() { editor.popup = None; } else if editor.prompt.is_some() { editor.prompt = None; }
-
-                        KeyCode::Up => editor.cursor_vertical(-1),
-                        KeyCode::Down => editor.cursor_vertical(1),
-                        KeyCode::Right => editor.cursor_horizontal(1),
-                        KeyCode::Left => editor.cursor_horizontal(-1),
-                        _ => {},
-                    }
+                            false
+                        }
+                        if editor.popup.is_some() {
+                            let popup = editor.popup.as_ref().unwrap().clone();
+                            if handle_prompt(&mut editor, popup, true) { break 'app; }
+                        } else if editor.prompt.is_some() {
+                            let prompt = editor.prompt.as_ref().unwrap().clone();
+                            if handle_prompt(&mut editor, prompt, false) { break 'app; }
+                        } else {
+                            editor.enter();
+                        }
+                    },
+                    KeyCode::Esc => if editor.popup.is_some() { editor.popup = None; } else if editor.prompt.is_some() { editor.prompt = None; } else { editor.search = None; }
+
+                    KeyCode::Up if editor.popup.is_some() => editor.scroll_popup(-1),
+                    KeyCode::Down if editor.popup.is_some() => editor.scroll_popup(1),
+                    KeyCode::PageUp if editor.popup.is_some() => editor.scroll_popup(-editor.popup_page()),
+                    KeyCode::PageDown if editor.popup.is_some() => editor.scroll_popup(editor.popup_page()),
+                    KeyCode::Up => editor.cursor_row(-1),
+                    KeyCode::Down => editor.cursor_row(1),
+                    KeyCode::Right => editor.cursor_horizontal(1),
+                    KeyCode::Left => editor.cursor_horizontal(-1),
+                    KeyCode::Home => editor.cursor_start_of_row(),
+                    KeyCode::End => editor.cursor_end_of_row(),
+                    KeyCode::PageUp => editor.page(-1),
+                    KeyCode::PageDown => editor.page(1),
+                    _ => {},
                 }
-            },
-            _ => {},
+            }
         }
+        editor.poll_lsp();
         editor.redraw()?;
     }
+    editor.remember_position();
+    editor.remove_swap();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An editor on `contents` with the default config, not attached to a terminal or a file
+    fn fox(contents: &str) -> Fox {
+        let (mut text, endings, line_ending, _) = split_lines(contents);
+        if text.is_empty() {
+            text.push(String::new());
+        }
+        let file = LoadedFile {
+            path_expanded: String::new(),
+            text,
+            endings,
+            line_ending,
+            bytes: None,
+            modified: None,
+            read_only: false,
+            new_file: true,
+            cursor: (0, 0),
+            scroll: 0,
+            status: String::new(),
+            syntax: carbon_dump::SYNTAX_SET.find_syntax_plain_text().clone(),
+        };
+        Fox::with_file("test", file, Config::default())
+    }
+
+    #[test]
+    fn edit_giant_line() {
+        let line = "x".repeat(1 << 20);
+        let mut fox = fox(&line);
+        fox.cursor_end_of_line();
+        fox.push_char('y');
+        assert_eq!(fox.cursor, (line.len() + 1, 0));
+        assert!(fox.text[0].ends_with("xy"));
+        fox.pop_char();
+        assert_eq!(fox.text[0], line);
+
+        // Past what a u16 column could hold
+        fox.cursor = (70_000, 0);
+        fox.highlight = fox.cursor;
+        fox.push_char('z');
+        assert_eq!(fox.cursor, (70_001, 0));
+        assert_eq!(&fox.text[0][69_999..70_002], "xzx");
+    }
+
+    #[test]
+    fn paste_keeps_indentation() {
+        let mut fox = fox("");
+        fox.paste("    if x {\n    }\n");
+        assert_eq!(fox.text, ["    if x {", "    }", ""]);
+        assert_eq!(fox.cursor, (0, 2));
+    }
+
+    #[test]
+    fn dedent_closing_bracket() {
+        let mut blank = fox("        ");
+        blank.cursor_end_of_line();
+        blank.push_char('}');
+        assert_eq!(blank.text[0], "    }");
+        assert_eq!(blank.cursor, (5, 0));
+
+        // Only on lines with nothing else on them
+        let mut code = fox("    x");
+        code.cursor_end_of_line();
+        code.push_char(')');
+        assert_eq!(code.text[0], "    x)");
+    }
+
+    #[test]
+    fn page_through_long_file() {
+        let lines: Vec<String> = (0..40_000).map(|i| i.to_string()).collect();
+        let mut fox = fox(&lines.join("\n"));
+        fox.go_to_line(32_760);
+        fox.page(1);
+        assert!(fox.cursor.1 > 32_767);
+        fox.cursor_end_of_file();
+        fox.page(-1);
+        assert!(fox.cursor.1 < 39_999);
+    }
+
+    #[test]
+    fn keep_each_line_ending() {
+        let mut fox = fox("a\r\nb\nc\r\n");
+        assert_eq!(fox.saved_text(), "a\r\nb\nc\r\n");
+        fox.cursor_end_of_line();
+        fox.enter();
+        fox.push_char('x');
+        assert_eq!(fox.saved_text(), "a\r\nx\r\nb\nc\r\n");
+        fox.undo();
+        assert_eq!(fox.saved_text(), "a\r\nb\nc\r\n");
+        fox.convert_line_endings("lf");
+        assert_eq!(fox.saved_text(), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn hex_rows_follow_the_screen() {
+        let mut fox = fox("");
+        fox.hex = Some((0..128).collect());
+        let offsets: Vec<usize> = (1..=8).map(|i| fox.hex_screen_row(i) * HEX_ROW).collect();
+        assert_eq!(offsets, [0x00, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70]);
+        fox.scroll = 2;
+        assert_eq!(fox.hex_screen_row(1), 2);
+        assert_eq!(fox.hex_screen_row(3), 4);
+    }
+
+    #[test]
+    fn scroll_just_enough() {
+        // Already on screen
+        assert_eq!(scroll_to_row(10, 15, 20), 10);
+        // Jumping up puts the row at the top
+        assert_eq!(scroll_to_row(50, 10, 20), 10);
+        // Jumping down puts the row at the bottom
+        assert_eq!(scroll_to_row(0, 100, 20), 81);
+        assert_eq!(scroll_to_row(0, 19, 20), 0);
+        assert_eq!(scroll_to_row(0, 20, 20), 1);
+        // No room for text still shows the cursor's row
+        assert_eq!(scroll_to_row(0, 5, 0), 5);
+    }
+
+    #[test]
+    fn keep_cursor_visible() {
+        let lines: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+        let mut long = fox(&lines.join("\n"));
+        let rows = long.text_rows(24) as usize;
+
+        long.ensure_cursor_visible_in(80, 24);
+        assert_eq!(long.scroll, 0);
+
+        long.cursor = (0, 99);
+        long.ensure_cursor_visible_in(80, 24);
+        assert_eq!(long.scroll, 100 - rows);
+
+        long.cursor = (0, 0);
+        long.ensure_cursor_visible_in(80, 24);
+        assert_eq!(long.scroll, 0);
+
+        // A file shorter than the screen never scrolls
+        let mut short = fox("a\nb\nc");
+        short.cursor = (0, 2);
+        short.ensure_cursor_visible_in(80, 24);
+        assert_eq!(short.scroll, 0);
+    }
+}
@@ -0,0 +1,234 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+// How long to wait for the server to answer the shutdown request when closing
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+}
+
+pub struct Diagnostic {
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+// What the reader thread passes back to the editor
+enum Message {
+    Initialized,
+    ShutDown,
+    Diagnostics(Vec<Diagnostic>),
+}
+
+// A running language server, talking JSON-RPC over its stdio
+pub struct Lsp {
+    child: Child,
+    stdin: Arc<Mutex<ChildStdin>>,
+    messages: mpsc::Receiver<Message>,
+    uri: String,
+    language_id: String,
+    version: i64,
+    ready: bool,
+    changed: bool, // The server hasn't seen the latest text yet
+}
+
+fn file_uri(path: &Path) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = String::from("file://");
+    for b in path.display().to_string().bytes() {
+        if b.is_ascii_alphanumeric() || b"/-_.~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", b));
+        }
+    }
+    uri
+}
+
+fn send(stdin: &Mutex<ChildStdin>, message: Value) {
+    let body = message.to_string();
+    if let Ok(mut stdin) = stdin.lock() {
+        let _ = write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = stdin.flush();
+    }
+}
+
+// Reads one message, None once the server is gone
+fn receive(reader: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn parse_diagnostics(params: &Value) -> Vec<Diagnostic> {
+    params["diagnostics"].as_array().map(|diagnostics| diagnostics.iter().map(|d| Diagnostic {
+        line: d["range"]["start"]["line"].as_u64().unwrap_or(0) as usize,
+        severity: match d["severity"].as_u64() {
+            Some(2) => Severity::Warning,
+            Some(3) => Severity::Information,
+            Some(4) => Severity::Hint,
+            _ => Severity::Error,
+        },
+        message: d["message"].as_str().unwrap_or_default().to_string(),
+    }).collect()).unwrap_or_default()
+}
+
+impl Lsp {
+    pub fn start(command: &str, path: &Path, language_id: &str) -> std::io::Result<Self> {
+        let mut args = command.split_whitespace();
+        let program = args.next().ok_or_else(|| std::io::Error::other("empty language server command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = Arc::new(Mutex::new(child.stdin.take().unwrap()));
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let uri = file_uri(path);
+
+        let (sender, messages) = mpsc::channel();
+        let writer = stdin.clone();
+        let our_uri = uri.clone();
+        std::thread::spawn(move || {
+            while let Some(message) = receive(&mut stdout) {
+                let message = match (message.get("id"), message["method"].as_str()) {
+                    // Requests from the server, none of them are supported so answer with nothing
+                    (Some(id), Some(_)) => {
+                        send(&writer, json!({"jsonrpc": "2.0", "id": id, "result": null}));
+                        continue;
+                    },
+                    (Some(id), None) if id == 1 => Message::Initialized,
+                    (Some(id), None) if id == 2 => Message::ShutDown,
+                    (None, Some("textDocument/publishDiagnostics")) if message["params"]["uri"] == our_uri.as_str() => {
+                        Message::Diagnostics(parse_diagnostics(&message["params"]))
+                    },
+                    _ => continue,
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let root = std::env::current_dir().map(|dir| file_uri(&dir)).ok();
+        send(&stdin, json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {
+                "processId": std::process::id(),
+                "rootUri": root,
+                "capabilities": {
+                    "textDocument": {
+                        "publishDiagnostics": {},
+                    },
+                },
+            },
+        }));
+
+        Ok(Self {
+            child,
+            stdin,
+            messages,
+            uri,
+            language_id: language_id.to_string(),
+            version: 0,
+            ready: false,
+            changed: true,
+        })
+    }
+
+    pub fn text_changed(&mut self) {
+        self.changed = true;
+    }
+
+    // Sends the latest text if needed, returns new diagnostics if the server published any.
+    // The text is only built when it is actually sent
+    pub fn poll(&mut self, text: impl Fn() -> String) -> Option<Vec<Diagnostic>> {
+        let mut diagnostics = None;
+        while let Ok(message) = self.messages.try_recv() {
+            match message {
+                Message::Initialized => {
+                    self.ready = true;
+                    send(&self.stdin, json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}));
+                    send(&self.stdin, json!({
+                        "jsonrpc": "2.0",
+                        "method": "textDocument/didOpen",
+                        "params": {
+                            "textDocument": {
+                                "uri": self.uri,
+                                "languageId": self.language_id,
+                                "version": self.version,
+                                "text": text(),
+                            },
+                        },
+                    }));
+                    self.changed = false;
+                },
+                Message::Diagnostics(d) => diagnostics = Some(d),
+                Message::ShutDown => {},
+            }
+        }
+        if self.ready && self.changed {
+            self.version += 1;
+            send(&self.stdin, json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": {
+                        "uri": self.uri,
+                        "version": self.version,
+                    },
+                    "contentChanges": [{ "text": text() }],
+                },
+            }));
+            self.changed = false;
+        }
+        diagnostics
+    }
+}
+
+impl Drop for Lsp {
+    fn drop(&mut self) {
+        // Ask nicely first, the server is only supposed to exit once it has answered
+        send(&self.stdin, json!({"jsonrpc": "2.0", "id": 2, "method": "shutdown"}));
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match self.messages.recv_timeout(left) {
+                Ok(Message::ShutDown) | Err(_) => break,
+                Ok(_) => {},
+            }
+        }
+        send(&self.stdin, json!({"jsonrpc": "2.0", "method": "exit"}));
+        while Instant::now() < deadline && matches!(self.child.try_wait(), Ok(None)) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
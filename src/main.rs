@@ -2,6 +2,8 @@ use clap::{Arg, Command};
 
 mod fox;
 mod config;
+mod state;
+mod lsp;
 
 fn main() {
     let matches = Command::new("fox")
@@ -13,13 +15,37 @@ fn main() {
             Command::new("help")
                 .about("help page")
         )
+        .subcommand(
+            Command::new("cat")
+                .about("print a file with syntax highlighting")
+                .arg(Arg::new("filename").required(true))
+        )
         .arg(
             Arg::new("filename")
         )
+        .arg(
+            Arg::new("no-alt-screen")
+                .long("no-alt-screen")
+                .help("draw in the normal screen buffer instead of the alternate screen")
+        )
         .get_matches();
 
-    if let Some(filename) = matches.get_one::<String>("filename") {
-        fox::run(filename).expect("Failed to run fox editor!");
+    if let Some(("cat", matches)) = matches.subcommand() {
+        let filename = matches.get_one::<String>("filename").expect("Filename is required");
+        match fox::cat(filename) {
+            // The reader went away, like `head` after enough lines
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {},
+            Err(e) => {
+                eprintln!("fox: {}", e);
+                std::process::exit(1);
+            },
+            Ok(()) => {},
+        }
+    } else if let Some(filename) = matches.get_one::<String>("filename") {
+        if let Err(e) = fox::run(filename, !matches.contains_id("no-alt-screen")) {
+            eprintln!("fox: {}", e);
+            std::process::exit(1);
+        }
     } else {
         panic!("How did we get here?");
     }
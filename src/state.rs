@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::config_location;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Position {
+    pub cursor: (usize, usize),
+    pub scroll: usize,
+}
+
+pub fn positions_location() -> std::io::Result<PathBuf> {
+    config_location().map(|p| p.with_file_name("positions.toml"))
+}
+
+fn positions() -> HashMap<String, Position> {
+    positions_location().ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|s| toml::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+pub fn position(path: &str) -> Option<Position> {
+    positions().remove(path)
+}
+
+pub fn remember_position(path: &str, position: Position) {
+    let mut positions = positions();
+    positions.insert(path.to_string(), position);
+    if let (Ok(location), Ok(s)) = (positions_location(), toml::to_string(&positions)) {
+        let _ = std::fs::write(location, s);
+    }
+}
+
+// How many files the recent files list keeps
+const RECENT_FILES: usize = 20;
+
+pub fn recent_location() -> std::io::Result<PathBuf> {
+    config_location().map(|p| p.with_file_name("recent.toml"))
+}
+
+// Paths with when they were last opened or saved, in milliseconds since the epoch
+fn recent() -> HashMap<String, u64> {
+    recent_location().ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|s| toml::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+// Recently used files, most recent first
+pub fn recent_files() -> Vec<String> {
+    let mut files: Vec<(String, u64)> = recent().into_iter().collect();
+    files.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+    files.into_iter().map(|(path, _)| path).collect()
+}
+
+pub fn touch_recent(path: &str) {
+    let mut recent = recent();
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or_default();
+    recent.insert(path.to_string(), now);
+    if recent.len() > RECENT_FILES {
+        let mut times: Vec<u64> = recent.values().copied().collect();
+        times.sort_unstable_by_key(|time| std::cmp::Reverse(*time));
+        let oldest = times[RECENT_FILES - 1];
+        recent.retain(|_, time| *time >= oldest);
+    }
+    if let (Ok(location), Ok(s)) = (recent_location(), toml::to_string(&recent)) {
+        let _ = std::fs::write(location, s);
+    }
+}